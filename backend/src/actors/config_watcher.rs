@@ -0,0 +1,117 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use actix::prelude::*;
+
+use crate::config_loader;
+use crate::model::SharedConfig;
+
+use super::messages::{ConfigChangedMessage, ConfigReloadMessage};
+use super::PlaceServer;
+
+/// Interval of config file modification checks
+const CONFIG_WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Reload config when its file is modified or on SIGHUP, then notify the place server
+pub struct ConfigWatcher {
+    path: PathBuf,
+    /// Command line flags, applied again on every reload
+    flags: serde_json::Value,
+    config: SharedConfig,
+    place_server: Addr<PlaceServer>,
+    /// Modification time of the file last loaded
+    modified: Option<SystemTime>,
+}
+
+impl Actor for ConfigWatcher {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.modified = self.file_modified();
+        ctx.run_interval(CONFIG_WATCH_INTERVAL, |act, ctx| {
+            let modified = act.file_modified();
+            if modified.is_some() && modified != act.modified {
+                act.modified = modified;
+                ctx.notify(ConfigReloadMessage);
+            }
+        });
+
+        #[cfg(unix)]
+        self.hangup_listen(ctx);
+    }
+}
+
+impl ConfigWatcher {
+    pub fn new(path: PathBuf, flags: serde_json::Value, config: SharedConfig, place_server: Addr<PlaceServer>) -> Self {
+        Self {
+            path,
+            flags,
+            config,
+            place_server,
+            modified: None,
+        }
+    }
+
+    fn file_modified(&self) -> Option<SystemTime> {
+        fs::metadata(&self.path).and_then(|metadata| metadata.modified()).ok()
+    }
+
+    /// Reload on every SIGHUP
+    #[cfg(unix)]
+    fn hangup_listen(&self, ctx: &mut Context<Self>) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(err) => {
+                log::error!("Failed to listen to SIGHUP, config is only reloaded on file change: {err}");
+                return;
+            },
+        };
+        let addr = ctx.address();
+        actix::spawn(async move {
+            while hangup.recv().await.is_some() {
+                log::info!("SIGHUP received, reloading config");
+                addr.do_send(ConfigReloadMessage);
+            }
+        });
+    }
+}
+
+impl Handler<ConfigReloadMessage> for ConfigWatcher {
+    type Result = ();
+
+    fn handle(&mut self, _msg: ConfigReloadMessage, _ctx: &mut Context<Self>) -> Self::Result {
+        let config = match config_loader::load(&self.path, &self.flags) {
+            Ok((config, _)) => config,
+            Err(err) => {
+                log::warn!("Config reload rejected, failed to load {}: {err}", self.path.display());
+                return;
+            },
+        };
+        if let Err(errors) = config.validate() {
+            for error in &errors.0 {
+                log::warn!("Config reload rejected, invalid {error}");
+            }
+            return;
+        }
+
+        let current_config = self.config.get();
+        if *current_config == config {
+            log::info!("Config unchanged, nothing to reload");
+            return;
+        }
+        let errors = current_config.reload_errors(&config);
+        if !errors.is_empty() {
+            for error in &errors {
+                log::warn!("Config reload rejected, {error}");
+            }
+            return;
+        }
+
+        self.config.set(config);
+        log::info!("Config reloaded from {}", self.path.display());
+        self.place_server.do_send(ConfigChangedMessage);
+    }
+}
@@ -1,15 +1,19 @@
 use actix::{Actor, Addr};
 use futures_util::stream::StreamExt as _; // get .next() working
 
+use crate::protocol;
+
 use super::{messages::{StopSession, WsMessage}, PlaceServer, PlaceSession};
 
 pub async fn handle_ws(
     uuid: String,
     session: actix_ws::Session,
     place_server: Addr<PlaceServer>,
-    mut msg_stream: actix_ws::MessageStream,
+    msg_stream: actix_ws::MessageStream,
 ) -> () {
     let place_session = PlaceSession::new(uuid, place_server, session).start();
+    let mut msg_stream = msg_stream.max_frame_size(protocol::MAX_FRAME_SIZE);
+    let mut close_reason = None;
 
     loop {
         match msg_stream.next().await {
@@ -17,6 +21,13 @@ pub async fn handle_ws(
             Some(Ok(ws_msg)) => {
                 place_session.send(WsMessage(ws_msg)).await.ok();
             },
+            Some(Err(actix_ws::ProtocolError::Overflow)) => {
+                close_reason = Some(actix_ws::CloseReason {
+                    code: actix_ws::CloseCode::Size,
+                    description: Some(format!("Frame larger than {} bytes", protocol::MAX_FRAME_SIZE)),
+                });
+                break;
+            },
             Some(Err(err)) => {
                 log::error!("{}",err);
                 break;
@@ -27,5 +38,5 @@ pub async fn handle_ws(
         };
     };
 
-    place_session.do_send(StopSession(None));
+    place_session.do_send(StopSession(close_reason));
 }
\ No newline at end of file
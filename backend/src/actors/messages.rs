@@ -1,9 +1,10 @@
+use std::sync::Arc;
 use std::time::Duration;
 
 use actix::prelude::*;
 use actix_ws as ws;
 
-pub use crate::model::UserPixelColorMessage;
+pub use crate::model::{Cooldown, SequencedPixelUpdate, UserPixelColorMessage};
 
 use super::PlaceSession;
 
@@ -33,3 +34,58 @@ pub struct DisconnectMessage {
 #[derive(Message, Clone)]
 #[rtype(result = "()")]
 pub struct OnlineUserCountMessage(pub usize);
+
+/// Pixel updates sent to a session as a single frame
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct PixelUpdatesMessage(pub Arc<[SequencedPixelUpdate]>);
+
+/// Session missed pixel updates, the client must load the whole canvas again
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct ReloadMessage;
+
+/// Every pixel update up to seq was sent to the session, those out of its view left out
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SyncedMessage(pub u64);
+
+/// Ask for updates after `from_seq`, `None` if some are not buffered anymore
+#[derive(Message)]
+#[rtype(result = "Option<Vec<SequencedPixelUpdate>>")]
+pub struct ResumeMessage {
+    pub uuid: String,
+    pub from_seq: u64,
+}
+
+/// Client quota state pushed to its session
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct CooldownMessage(pub Cooldown);
+
+/// Ask for the client quota state, with pixels refilled if due, `None` if it could not be read
+#[derive(Message)]
+#[rtype(result = "Option<Cooldown>")]
+pub struct CooldownRequestMessage {
+    pub uuid: String,
+}
+
+/// Config was reloaded, sent to the place server then to every session
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct ConfigChangedMessage;
+
+/// Read the config file again and apply it if only reloadable fields changed
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ConfigReloadMessage;
+
+/// Add or remove chunks from the session view, a session without view gets every update
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SubscribeMessage {
+    pub uuid: String,
+    /// Chunk indices `(chunk_index_x, chunk_index_y)`
+    pub chunks: Vec<(usize, usize)>,
+    pub subscribe: bool,
+}
@@ -1,6 +1,11 @@
 mod place_server;
 pub use place_server::*;
 
+pub mod place_relay;
+
+pub mod config_watcher;
+pub use config_watcher::ConfigWatcher;
+
 pub mod messages;
 
 pub mod handler;
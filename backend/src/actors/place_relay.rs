@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+use futures_util::Stream;
+use redis::{aio::ConnectionManager, AsyncCommands, RedisResult};
+use uuid::Uuid;
+
+use crate::model::{self, SequencedPixelUpdate, StorageKind};
+
+/// Channel of accepted pixel updates, payload is a serialized `SequencedPixelUpdate`
+pub const PIXEL_CHANNEL: &str = "place_pixels";
+/// Channel of instance online counts, payload is a serialized `InstanceOnlineCount`
+pub const ONLINE_CHANNEL: &str = "place_online";
+
+/// Instance online count expires if the instance stops refreshing it
+const ONLINE_COUNT_EXPIRY: Duration = Duration::from_secs(30);
+/// Interval of online count refresh, must be lower than expiry
+pub const ONLINE_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+/// Delay before subscribing again after losing relay channels
+pub const RELAY_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Online count of a single instance
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstanceOnlineCount {
+    pub instance_id: String,
+    pub count: usize,
+}
+
+impl InstanceOnlineCount {
+    /// Count then instance id, separated by a space
+    pub fn serialize(&self) -> String {
+        format!("{} {}", self.count, self.instance_id)
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Self, &'static str> {
+        let data = std::str::from_utf8(data).map_err(|_| "Error deserializing online count, invalid UTF-8")?;
+        let (count, instance_id) = data.split_once(' ').ok_or("Error deserializing online count, missing instance id")?;
+
+        Ok(Self {
+            instance_id: instance_id.to_string(),
+            count: count.parse().map_err(|_| "Error deserializing online count, invalid count")?,
+        })
+    }
+}
+
+/// Latest online count relayed by every instance
+#[derive(Debug, Default)]
+pub struct OnlineCounts(HashMap<String, (usize, Instant)>);
+
+impl OnlineCounts {
+    pub fn update(&mut self, online_count: InstanceOnlineCount) {
+        self.0.insert(online_count.instance_id, (online_count.count, Instant::now()));
+    }
+
+    /// Sum of counts of running instances, forgetting instances which stopped refreshing theirs
+    pub fn total(&mut self) -> usize {
+        self.0.retain(|_, (_, updated)| updated.elapsed() < ONLINE_COUNT_EXPIRY);
+        self.0.values().map(|(count, _)| count).sum()
+    }
+}
+
+/// Relay of pixel updates and online counts between `PlaceServer` instances over redis pub/sub
+#[derive(Clone)]
+pub struct PlaceRelay {
+    redis_client: redis::Client,
+    con: ConnectionManager,
+    instance_id: String,
+}
+
+impl PlaceRelay {
+    pub async fn new(redis_client: redis::Client) -> RedisResult<Self> {
+        let con = ConnectionManager::new(redis_client.clone()).await?;
+
+        Ok(Self {
+            redis_client,
+            con,
+            instance_id: Uuid::new_v4().to_string(),
+        })
+    }
+
+    /// Subscribe to relay channels on a dedicated connection
+    pub async fn subscribe(&self) -> RedisResult<impl Stream<Item = redis::Msg>> {
+        let mut pubsub = self.redis_client.get_async_pubsub().await?;
+        pubsub.subscribe(PIXEL_CHANNEL).await?;
+        pubsub.subscribe(ONLINE_CHANNEL).await?;
+
+        Ok(pubsub.into_on_message())
+    }
+
+    pub async fn publish_pixel(&self, pixel_update: &SequencedPixelUpdate) -> RedisResult<()> {
+        let mut con = self.con.clone();
+        con.publish::<_, _, ()>(PIXEL_CHANNEL, pixel_update.serialize()).await
+    }
+
+    /// Send online count of this instance to every instance
+    pub async fn publish_online(&self, local_count: usize) -> RedisResult<()> {
+        let mut con = self.con.clone();
+        let online_count = InstanceOnlineCount { instance_id: self.instance_id.clone(), count: local_count };
+        con.publish::<_, _, ()>(ONLINE_CHANNEL, online_count.serialize()).await
+    }
+}
+
+/// Create a relay when instances can share state, thus only with redis storage
+pub async fn open_relay(config: &model::Config) -> Result<Option<PlaceRelay>, Box<dyn Error>> {
+    match config.storage {
+        StorageKind::Redis => {
+            log::info!("Relaying pixel updates over redis pub/sub");
+            let redis_client = redis::Client::open(config.redis_url.clone())?;
+            Ok(Some(PlaceRelay::new(redis_client).await?))
+        },
+        _ => Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn online_count_round_trip() {
+        let online_count = InstanceOnlineCount { instance_id: Uuid::new_v4().to_string(), count: 42 };
+        let decoded = InstanceOnlineCount::deserialize(online_count.serialize().as_bytes()).unwrap();
+        assert_eq!(decoded, online_count);
+
+        assert!(InstanceOnlineCount::deserialize(b"42").is_err());
+        assert!(InstanceOnlineCount::deserialize(b"many instance").is_err());
+    }
+
+    #[test]
+    fn online_counts_keep_latest_per_instance() {
+        let mut online_counts = OnlineCounts::default();
+        online_counts.update(InstanceOnlineCount { instance_id: "a".to_string(), count: 3 });
+        online_counts.update(InstanceOnlineCount { instance_id: "b".to_string(), count: 4 });
+        online_counts.update(InstanceOnlineCount { instance_id: "a".to_string(), count: 1 });
+        assert_eq!(online_counts.total(), 5);
+    }
+}
@@ -1,31 +1,89 @@
-use std::collections::HashMap;
-
-use redis::{self, Commands};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem;
+use std::sync::Arc;
+use std::time::Duration;
 
 use actix_ws as ws;
 use actix::prelude::*;
 
-use crate::{controller::canvas_redis_set, model::PixelColorUpdateMessage};
+use crate::model::{Client, Cooldown, PlacementError, SequencedPixelUpdate, SharedConfig};
 use crate::model;
+use crate::store::{CanvasStore, Placement};
 
-use super::messages::{ConnectMessage, DisconnectMessage, OnlineUserCountMessage};
+use super::messages::{ConfigChangedMessage, ConnectMessage, CooldownMessage, CooldownRequestMessage, DisconnectMessage, OnlineUserCountMessage, PixelUpdatesMessage, ReloadMessage, ResumeMessage, SubscribeMessage, SyncedMessage};
+use super::place_relay::{InstanceOnlineCount, OnlineCounts, PlaceRelay, ONLINE_CHANNEL, ONLINE_REFRESH_INTERVAL, PIXEL_CHANNEL, RELAY_RETRY_DELAY};
 use super::PlaceSession;
 
+/// Number of recent pixel updates kept for resuming sessions
+const UPDATES_BUFFER_CAPACITY: usize = 16384;
+/// Delay between `Synced` sent to sessions which skipped updates out of their view
+const SYNCED_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Sequence numbers of a session, to tell it which updates out of view it skipped
+#[derive(Default)]
+struct SessionSeqs {
+    /// Latest sequence number flushed to the session, in view or not
+    delivered: u64,
+    /// Latest sequence number sent to the client, by a pixel update or `Synced`
+    sent: u64,
+}
+
 pub struct PlaceServer {
-    config: model::Config,
-    redis_client: redis::Client,
+    config: SharedConfig,
+    store: Arc<dyn CanvasStore>,
     sessions: HashMap<String, Addr<PlaceSession>>,
+    /// Chunks in view of sessions which subscribed, other sessions get every update
+    subscriptions: HashMap<String, HashSet<(usize, usize)>>,
+    /// Sequence numbers flushed and sent to every session
+    session_seqs: HashMap<String, SessionSeqs>,
+    /// Other instances link, `None` when running alone
+    relay: Option<PlaceRelay>,
+    /// Relay channels are subscribed, updates of other instances are missed otherwise
+    relay_subscribed: bool,
+    /// Online counts relayed by every instance, this one included
+    online_counts: OnlineCounts,
+    /// Latest placement sequence number seen
+    last_seq: u64,
+    /// Recent pixel updates in reception order, sent again to resuming sessions
+    updates: VecDeque<SequencedPixelUpdate>,
+    /// Pixel updates waiting for next tick
+    pending_updates: Vec<SequencedPixelUpdate>,
 }
 impl Actor for PlaceServer {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.last_seq_load(ctx);
+        // intervals cannot be reloaded
+        let config = self.config.get();
+        if !config.tick_interval.is_zero() {
+            ctx.run_interval(config.tick_interval, |act, _ctx| act.flush_pixel_updates());
+        }
+        ctx.run_interval(SYNCED_INTERVAL, |act, _ctx| act.send_synced());
+        if !config.snapshot_interval.is_zero() {
+            ctx.run_interval(config.snapshot_interval, |act, ctx| act.snapshot_take(ctx));
+        }
+        if self.relay.is_some() {
+            self.relay_subscribe(ctx);
+            ctx.run_interval(ONLINE_REFRESH_INTERVAL, |act, ctx| act.online_changed(ctx));
+        }
+    }
 }
 
 impl PlaceServer {
-    pub fn new(redis_client: redis::Client, config: model::Config) -> Self {
+    pub fn new(store: Arc<dyn CanvasStore>, config: SharedConfig, relay: Option<PlaceRelay>) -> Self {
         Self {
             config,
-            redis_client,
-            sessions: HashMap::new()
+            store,
+            sessions: HashMap::new(),
+            subscriptions: HashMap::new(),
+            session_seqs: HashMap::new(),
+            relay,
+            relay_subscribed: false,
+            online_counts: OnlineCounts::default(),
+            last_seq: 0,
+            updates: VecDeque::with_capacity(UPDATES_BUFFER_CAPACITY),
+            pending_updates: Vec::new(),
         }
     }
     fn send_online(&self, message_count: OnlineUserCountMessage)
@@ -36,30 +94,224 @@ impl PlaceServer {
     }
 
 
-    fn send_pixel_update(&self, msg: PixelColorUpdateMessage)
+    fn send_pixel_update(&mut self, msg: SequencedPixelUpdate)
+    {
+        self.last_seq = self.last_seq.max(msg.seq);
+        if self.updates.len() == UPDATES_BUFFER_CAPACITY {
+            self.updates.pop_front();
+        }
+        self.updates.push_back(msg.clone());
+
+        self.pending_updates.push(msg);
+        if self.config.get().tick_interval.is_zero() {
+            self.flush_pixel_updates();
+        }
+    }
+
+    /// Send pending pixel updates, one frame per session
+    fn flush_pixel_updates(&mut self)
+    {
+        if self.pending_updates.is_empty() {
+            return;
+        }
+        let pending_updates: Arc<[SequencedPixelUpdate]> = mem::take(&mut self.pending_updates).into();
+        let pending_seq = pending_updates.iter().map(|update| update.seq).max().unwrap_or_default();
+
+        for (uuid, session) in &self.sessions {
+            // sessions without view share the same updates
+            let updates: Arc<[SequencedPixelUpdate]> = if self.subscriptions.contains_key(uuid) {
+                pending_updates.iter().filter(|update| self.is_in_view(uuid, update)).cloned().collect()
+            } else {
+                pending_updates.clone()
+            };
+            let updates_seq = updates.iter().map(|update| update.seq).max().unwrap_or_default();
+
+            if !updates.is_empty() {
+                session.do_send(PixelUpdatesMessage(updates));
+            }
+            let seqs = self.session_seqs.entry(uuid.clone()).or_default();
+            seqs.delivered = seqs.delivered.max(pending_seq);
+            seqs.sent = seqs.sent.max(updates_seq);
+        }
+    }
+
+    /// Send the latest flushed sequence number to sessions which skipped updates out of view since the last one
+    ///
+    /// Otherwise the client resumes from its latest update in view, and out of view ones may not be buffered anymore.
+    fn send_synced(&mut self)
+    {
+        for (uuid, session) in &self.sessions {
+            let Some(seqs) = self.session_seqs.get_mut(uuid) else { continue };
+            if seqs.sent < seqs.delivered {
+                session.do_send(SyncedMessage(seqs.delivered));
+                seqs.sent = seqs.delivered;
+            }
+        }
+    }
+
+    /// Whether the pixel update is in a chunk viewed by the session
+    fn is_in_view(&self, uuid: &str, msg: &SequencedPixelUpdate) -> bool
+    {
+        let Some(chunks) = self.subscriptions.get(uuid) else { return true };
+
+        let (chunk_index, _) = self.config.get().canvas_pos_to_chunk_location(msg.pixel_update.pos_x.into(), msg.pixel_update.pos_y.into());
+        chunks.contains(&chunk_index)
+    }
+
+    /// Broadcast an accepted pixel, through the relay to reach every instance
+    fn publish_pixel_update(&mut self, msg: SequencedPixelUpdate, ctx: &mut Context<Self>)
+    {
+        let Some(relay) = self.relay.clone() else {
+            self.send_pixel_update(msg);
+            return;
+        };
+
+        // own updates only come back through the subscription
+        let subscribed = self.relay_subscribed;
+        if !subscribed {
+            self.send_pixel_update(msg.clone());
+        }
+
+        async move {
+            let res = relay.publish_pixel(&msg).await;
+            (msg, res)
+        }
+        .into_actor(self)
+        .map(move |(msg, res), act, _ctx| {
+            if let Err(err) = res {
+                log::error!("Failed to relay pixel update: {err}");
+                if subscribed {
+                    act.send_pixel_update(msg);
+                }
+            }
+        })
+        .spawn(ctx);
+    }
+
+    /// Local session count changed, send global count to sessions
+    fn online_changed(&self, ctx: &mut Context<Self>)
+    {
+        let local_count = self.sessions.len();
+        let Some(relay) = self.relay.clone() else {
+            self.send_online(OnlineUserCountMessage(local_count));
+            return;
+        };
+
+        async move { relay.publish_online(local_count).await }
+            .into_actor(self)
+            .map(move |res, act, _ctx| {
+                if let Err(err) = res {
+                    log::error!("Failed to relay online count: {err}");
+                    act.send_online(OnlineUserCountMessage(local_count));
+                }
+            })
+            .spawn(ctx);
+    }
+
+    /// Start sequence numbers from the latest stored placement
+    fn last_seq_load(&self, ctx: &mut Context<Self>)
+    {
+        let store = self.store.clone();
+        async move { store.history_seq().await }
+            .into_actor(self)
+            .map(|res, act, _ctx| match res {
+                Ok(seq) => act.last_seq = act.last_seq.max(seq),
+                Err(err) => log::error!("Failed to get latest placement sequence number: {err}"),
+            })
+            .spawn(ctx);
+    }
+
+    /// Save a canvas snapshot for time travel
+    fn snapshot_take(&self, ctx: &mut Context<Self>)
+    {
+        let store = self.store.clone();
+        async move {
+            match store.snapshot_take().await {
+                Ok(snapshot) => log::info!("Saved canvas snapshot at {}", snapshot.timestamp),
+                Err(err) => log::error!("Failed to save canvas snapshot: {err}"),
+            }
+        }
+        .into_actor(self)
+        .spawn(ctx);
+    }
+
+    /// Updates of other instances were missed while unsubscribed, connected sessions load the whole canvas again
+    fn relay_gap(&self, ctx: &mut Context<Self>)
     {
+        // resuming sessions see the missed sequence numbers as a gap
+        self.last_seq_load(ctx);
+
+        if !self.sessions.is_empty() {
+            log::warn!("Relay channels subscribed again, reloading {} sessions", self.sessions.len());
+        }
         for session in self.sessions.values() {
-            session.do_send(msg.clone());
+            session.do_send(ReloadMessage);
         }
     }
+
+    fn relay_subscribe(&self, ctx: &mut Context<Self>)
+    {
+        let Some(relay) = self.relay.clone() else { return };
+
+        async move { relay.subscribe().await }
+            .into_actor(self)
+            .map(|res, act, ctx| match res {
+                Ok(stream) => {
+                    ctx.add_stream(stream);
+                    act.relay_subscribed = true;
+                    act.relay_gap(ctx);
+                },
+                Err(err) => {
+                    log::error!("Failed to subscribe to relay channels: {err}");
+                    ctx.run_later(RELAY_RETRY_DELAY, |act, ctx| act.relay_subscribe(ctx));
+                }
+            })
+            .spawn(ctx);
+    }
+}
+
+impl StreamHandler<redis::Msg> for PlaceServer {
+    fn handle(&mut self, msg: redis::Msg, _ctx: &mut Context<Self>) {
+        match msg.get_channel_name() {
+            PIXEL_CHANNEL => match SequencedPixelUpdate::deserialize(msg.get_payload_bytes()) {
+                Ok(pixel_update) => self.send_pixel_update(pixel_update),
+                Err(err) => log::error!("Invalid relayed pixel update: {err}"),
+            },
+            ONLINE_CHANNEL => match InstanceOnlineCount::deserialize(msg.get_payload_bytes()) {
+                Ok(online_count) => {
+                    self.online_counts.update(online_count);
+                    let count = self.online_counts.total();
+                    self.send_online(OnlineUserCountMessage(count));
+                },
+                Err(err) => log::error!("Invalid relayed online count: {err}"),
+            },
+            channel => log::warn!("Message from unknown relay channel {channel}"),
+        }
+    }
+
+    fn finished(&mut self, ctx: &mut Context<Self>) {
+        // keep server running and subscribe again
+        log::error!("Lost relay channels subscription");
+        self.relay_subscribed = false;
+        ctx.run_later(RELAY_RETRY_DELAY, |act, ctx| act.relay_subscribe(ctx));
+    }
 }
 
 impl Handler<ConnectMessage> for PlaceServer {
     type Result = ();
 
-    fn handle(&mut self, msg: ConnectMessage, _: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: ConnectMessage, ctx: &mut Context<Self>) -> Self::Result {
         log::info!("Starting PlaceSession for #{}", msg.uuid.clone());
         self.sessions.insert(msg.uuid, msg.addr);
 
-        let message_count = OnlineUserCountMessage(self.sessions.len());
-        self.send_online(message_count);
+        self.online_changed(ctx);
     }
 }
 
 impl Handler<DisconnectMessage> for PlaceServer {
     type Result = ();
 
-    fn handle(&mut self, msg: DisconnectMessage, _ctx: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: DisconnectMessage, ctx: &mut Context<Self>) -> Self::Result {
         match match msg.close_reason {
             Some(reason) => { match reason.code {
                     ws::CloseCode::Normal | ws::CloseCode::Away => { None },
@@ -77,65 +329,175 @@ impl Handler<DisconnectMessage> for PlaceServer {
             }
         };
         self.sessions.remove(&msg.uuid);
+        self.subscriptions.remove(&msg.uuid);
+        self.session_seqs.remove(&msg.uuid);
 
-        let count = self.sessions.len();
-        let message_count = OnlineUserCountMessage(count);
-        self.send_online(message_count);
+        self.online_changed(ctx);
     }
 }
 
 impl Handler<model::UserPixelColorMessage> for PlaceServer {
-    type Result = Result<(), String>;
+    type Result = ResponseActFuture<Self, Result<u64, PlacementError>>;
 
     fn handle(&mut self, msg: model::UserPixelColorMessage, _ctx: &mut Context<Self>) -> Self::Result {
         // log::info!("Received new pixel color message: {:?}", &msg);
 
-        let mut con = self.redis_client.get_connection()
-            .map_err(|e| e.to_string())?;
-        let uuid = msg.uuid;
-        let pixel_update = msg.pixel_update;
-        let config = &self.config;
+        let store = self.store.clone();
+        let config = self.config.get();
+        let session = self.sessions.get(&msg.uuid).cloned();
+
+        Box::pin(async move {
+            let uuid = msg.uuid;
+            let pixel_update = msg.pixel_update;
 
-        if pixel_update.pos_x > (config.canvas_width as u16) || pixel_update.pos_y > (config.canvas_height as u16) {
-            return Err("Invalid position in canvas".to_string())
+            if !config.features.placement {
+                return Err(PlacementError::Disabled)
+            }
+            if usize::from(pixel_update.pos_x) >= config.canvas_width || usize::from(pixel_update.pos_y) >= config.canvas_height {
+                return Err(PlacementError::InvalidPosition)
+            }
+            if usize::from(pixel_update.color) >= config.active_color_count() {
+                return Err(PlacementError::InvalidColor)
+            }
+
+            // spend client pixel and update db
+            let placement = store.pixel_place(&uuid, &pixel_update).await.map_err(|err| {
+                log::error!("Failed to place pixel: {err}");
+                PlacementError::Storage
+            })?;
+            Ok((placement, pixel_update))
         }
+        .into_actor(self)
+        .map(move |res, act, ctx| {
+            let (placement, pixel_update) = res?;
+            if let Some(session) = session {
+                session.do_send(CooldownMessage(placement.client().cooldown(&act.config.get())));
+            }
 
+            match placement {
+                Placement::Placed(_, seq) => {
+                    // notify sessions
+                    act.publish_pixel_update(SequencedPixelUpdate { seq, pixel_update }, ctx);
+                    Ok(seq)
+                },
+                Placement::NoPixelsLeft(_) => Err(PlacementError::NoPixelsLeft),
+            }
+        }))
+    }
+}
 
-        // get client
-        let redis_result = con.get::<String, String>(uuid.clone());
-        let mut client = model::Client::from_redis(redis_result, config.base_pixel_amount);
+impl Handler<CooldownRequestMessage> for PlaceServer {
+    type Result = ResponseFuture<Option<Cooldown>>;
 
-        let current_timestamp = model::Client::timestamp_now();
-        let duration_secs = current_timestamp - client.last_timestamp;
-        let timeout_secs = config.timeout.as_secs();
+    fn handle(&mut self, msg: CooldownRequestMessage, _ctx: &mut Context<Self>) -> Self::Result {
+        let store = self.store.clone();
+        let config = self.config.get();
 
-        // update client
-        // agree with 1s margin
-        if client.remaining_pixels == 0 && duration_secs >= timeout_secs - 1 {
-            client.remaining_pixels = config.base_pixel_amount;
-            client.last_timestamp = current_timestamp;
+        Box::pin(async move {
+            let mut client = match store.client_get(&msg.uuid).await {
+                Ok(client) => client.unwrap_or_else(|| Client::new(config.base_pixel_amount)),
+                Err(err) => {
+                    log::error!("Failed to get client cooldown: {err}");
+                    return None;
+                },
+            };
+
+            // refill is only shown, next placement saves it
+            client.refill(&config, Client::timestamp_now());
+            Some(client.cooldown(&config))
+        })
+    }
+}
+
+impl Handler<ResumeMessage> for PlaceServer {
+    type Result = Option<Vec<SequencedPixelUpdate>>;
+
+    fn handle(&mut self, msg: ResumeMessage, _ctx: &mut Context<Self>) -> Self::Result {
+        let missed = updates_after(&self.updates, msg.from_seq, self.last_seq)?;
+        let missed: Vec<_> = missed.into_iter().filter(|update| self.is_in_view(&msg.uuid, update)).collect();
+
+        let seqs = self.session_seqs.entry(msg.uuid).or_default();
+        seqs.delivered = seqs.delivered.max(self.last_seq);
+        seqs.sent = seqs.sent.max(missed.iter().map(|update| update.seq).max().unwrap_or(msg.from_seq));
+        Some(missed)
+    }
+}
+
+/// Buffered updates after `from_seq` in sequence order, `None` unless every one up to `last_seq` is buffered
+fn updates_after(updates: &VecDeque<SequencedPixelUpdate>, from_seq: u64, last_seq: u64) -> Option<Vec<SequencedPixelUpdate>> {
+    // session seq from another canvas lifetime, e.g. memory store restarted
+    if from_seq > last_seq {
+        return None;
+    }
+
+    let mut missed: Vec<_> = updates.iter()
+        .filter(|update| update.seq > from_seq)
+        .cloned()
+        .collect();
+    missed.sort_by_key(|update| update.seq);
+    missed.dedup_by_key(|update| update.seq);
+
+    // updates older than the buffer or lost by pub/sub while resubscribing leave gaps
+    missed.iter().map(|update| update.seq).eq(from_seq + 1..=last_seq).then_some(missed)
+}
+
+impl Handler<SubscribeMessage> for PlaceServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeMessage, _ctx: &mut Context<Self>) -> Self::Result {
+        let (chunk_rows, chunk_cols) = self.config.get().canvas_chunks();
+        let valid_chunks = msg.chunks.into_iter()
+            .filter(|(chunk_index_x, chunk_index_y)| *chunk_index_x < chunk_rows && *chunk_index_y < chunk_cols);
+
+        if msg.subscribe {
+            self.subscriptions.entry(msg.uuid).or_default().extend(valid_chunks);
+        } else if let Some(chunks) = self.subscriptions.get_mut(&msg.uuid) {
+            // unsubscribing without a view keeps every update
+            for chunk_index in valid_chunks {
+                chunks.remove(&chunk_index);
+            }
         }
+    }
+}
 
-        // reduce pixel number
-        if client.remaining_pixels == 0 {
-            return Err("No pixels left".to_string());
-        } else {
-            client.remaining_pixels -= 1;
+impl Handler<ConfigChangedMessage> for PlaceServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: ConfigChangedMessage, _ctx: &mut Context<Self>) -> Self::Result {
+        for session in self.sessions.values() {
+            session.do_send(msg.clone());
         }
+    }
+}
 
-        // save client
-        let client_string = client.encode_json()
-            .map_err(|e| e.to_string())?;
-        con.set(uuid, client_string)
-            .map_err(|e| e.to_string())?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::PixelColorUpdateMessage;
 
-        // update db
-        canvas_redis_set(&self.redis_client, config, &pixel_update)?;
+    fn buffered(seqs: &[u64]) -> VecDeque<SequencedPixelUpdate> {
+        seqs.iter()
+            .map(|&seq| SequencedPixelUpdate { seq, pixel_update: PixelColorUpdateMessage { pos_x: 0, pos_y: 0, color: 1 } })
+            .collect()
+    }
 
+    fn seqs(updates: Option<Vec<SequencedPixelUpdate>>) -> Option<Vec<u64>> {
+        updates.map(|updates| updates.iter().map(|update| update.seq).collect())
+    }
 
-        // notify sessions
-        self.send_pixel_update(pixel_update);
+    #[test]
+    fn updates_after_in_sequence_order() {
+        let updates = buffered(&[4, 6, 5, 7]);
+        assert_eq!(seqs(updates_after(&updates, 4, 7)), Some(vec![5, 6, 7]));
+        assert_eq!(seqs(updates_after(&updates, 7, 7)), Some(vec![]));
+    }
 
-        Ok(())
+    #[test]
+    fn updates_after_refused_with_gaps() {
+        // 6 lost by pub/sub, 1 to 3 dropped from the buffer
+        let updates = buffered(&[4, 5, 7]);
+        assert_eq!(seqs(updates_after(&updates, 4, 7)), None);
+        assert_eq!(seqs(updates_after(&updates, 2, 5)), None);
+        assert_eq!(seqs(updates_after(&updates, 8, 7)), None);
     }
 }
@@ -1,13 +1,19 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use actix_ws::{self as ws, CloseReason, Session};
 
 use actix::prelude::*;
 
-use crate::model;
+use crate::model::{Client, Cooldown, PlacementError};
+use crate::protocol::{self, ClientMessage, ErrorCode, ServerMessage};
 
 use super::place_server::PlaceServer;
-use super::messages::{ConnectMessage, DisconnectMessage, OnlineUserCountMessage, UserPixelColorMessage, StopSession, WsMessage};
+use super::messages::{ConfigChangedMessage, ConnectMessage, CooldownMessage, CooldownRequestMessage, DisconnectMessage, OnlineUserCountMessage, PixelUpdatesMessage, ReloadMessage, ResumeMessage, SubscribeMessage, SyncedMessage, UserPixelColorMessage, StopSession, WsMessage};
+
+/// Delay for the client to send `Hello` before being closed
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Invalid messages after which the session is closed with a policy violation
+const MAX_VIOLATIONS: u32 = 10;
 
 pub struct PlaceSession {
     uuid: String,
@@ -16,6 +22,12 @@ pub struct PlaceSession {
     start: Instant,
     session: Session,
     close_reason: Option<ws::CloseReason>,
+    /// Client sent a supported `Hello`, the session joins the place server only then
+    handshake_done: bool,
+    /// Invalid messages sent by the client
+    violations: u32,
+    /// Pending cooldown request at the next refill
+    refill_timer: Option<SpawnHandle>,
 }
 
 impl PlaceSession {
@@ -26,6 +38,9 @@ impl PlaceSession {
             start: Instant::now(),
             session,
             close_reason: None,
+            handshake_done: false,
+            violations: 0,
+            refill_timer: None,
         }
     }
 
@@ -36,8 +51,141 @@ impl PlaceSession {
             session.close(msg).await.ok();
         }
         .into_actor(self)
+        .map(|_, _act, ctx| ctx.stop()) // stop once close frame is sent
+        .wait(ctx);
+    }
+
+    fn send(&self, message: ServerMessage, ctx: &mut Context<Self>) {
+        let mut session = self.session.clone();
+        async move {
+            session.binary(message.encode()).await.ok();
+        }
+        .into_actor(self)
         .wait(ctx);
-        ctx.stop();
+    }
+
+    /// Close clients which do not speak this protocol, reply an error to the others
+    fn invalid_message(&mut self, error: &str, ctx: &mut Context<Self>) {
+        if !self.handshake_done {
+            self.close(Some(CloseReason {
+                code: ws::CloseCode::Other(protocol::CLOSE_HANDSHAKE_REQUIRED),
+                description: Some(format!("Protocol handshake required, expected version {}", protocol::PROTOCOL_VERSION)),
+            }), ctx);
+            return;
+        }
+
+        if self.violation(ctx) {
+            return;
+        }
+        self.send(ServerMessage::Error { code: ErrorCode::InvalidMessage, request_id: 0, message: error.to_string() }, ctx);
+    }
+
+    /// Count a message only a broken or malicious client sends, true when the session is closed for it
+    fn violation(&mut self, ctx: &mut Context<Self>) -> bool {
+        self.violations += 1;
+        if self.violations < MAX_VIOLATIONS {
+            return false;
+        }
+
+        if self.violations == MAX_VIOLATIONS {
+            log::warn!("Closing session {} after {} invalid messages", self.uuid, self.violations);
+            self.close(Some(CloseReason {
+                code: ws::CloseCode::Policy,
+                description: Some("Too many invalid messages".to_string()),
+            }), ctx);
+        }
+        true
+    }
+
+    fn handshake(&mut self, version: u8, ctx: &mut Context<Self>) {
+        if version != protocol::PROTOCOL_VERSION {
+            self.close(Some(CloseReason {
+                code: ws::CloseCode::Other(protocol::CLOSE_UNSUPPORTED_VERSION),
+                description: Some(format!("Unsupported protocol version {version}, expected version {}", protocol::PROTOCOL_VERSION)),
+            }), ctx);
+            return;
+        }
+
+        self.handshake_done = true;
+        self.send(ServerMessage::Welcome { version }, ctx);
+
+        self.place_server.do_send(ConnectMessage {
+            uuid: self.uuid.clone(),
+            addr: ctx.address()
+        });
+        self.cooldown_request(ctx);
+    }
+
+    fn cooldown_request(&mut self, ctx: &mut Context<Self>) {
+        self.place_server.send(CooldownRequestMessage { uuid: self.uuid.clone() })
+            .into_actor(self)
+            .map(|res, act, ctx| match res {
+                Ok(Some(cooldown)) => act.cooldown(cooldown, ctx),
+                Ok(None) => {},
+                Err(err) => log::error!("Failed to request client cooldown: {err}"),
+            })
+            .spawn(ctx);
+    }
+
+    /// Push quota state, and push it again at refill time
+    fn cooldown(&mut self, cooldown: Cooldown, ctx: &mut Context<Self>) {
+        if let Some(handle) = self.refill_timer.take() {
+            ctx.cancel_future(handle);
+        }
+        self.send(ServerMessage::Cooldown(cooldown), ctx);
+
+        if cooldown.refilling {
+            let delay = cooldown.next_timestamp.saturating_sub(Client::timestamp_now()).max(1);
+            self.refill_timer = Some(ctx.run_later(Duration::from_secs(delay), |act, ctx| {
+                act.refill_timer = None;
+                act.cooldown_request(ctx);
+            }));
+        }
+    }
+
+    fn client_message(&mut self, data: &[u8], ctx: &mut Context<Self>) {
+        let message = match ClientMessage::decode(data) {
+            Ok(message) => message,
+            Err(err) => return self.invalid_message(err, ctx),
+        };
+
+        match message {
+            ClientMessage::Hello { version } if !self.handshake_done => self.handshake(version, ctx),
+            _ if !self.handshake_done => self.invalid_message("Expected hello message", ctx),
+            ClientMessage::Hello { .. } => self.invalid_message("Handshake already done", ctx),
+            ClientMessage::Heartbeat => self.send(ServerMessage::Heartbeat, ctx),
+            ClientMessage::Place { request_id, pixel_update } => {
+                self.place_server.send(UserPixelColorMessage { uuid: self.uuid.clone(), pixel_update })
+                    .into_actor(self)
+                    .map(move |res, act, ctx| {
+                        let result = res.unwrap_or_else(|err| {
+                            log::error!("Failed to send placement to place server: {err}");
+                            Err(PlacementError::Storage)
+                        });
+                        match result {
+                            Ok(seq) => act.send(ServerMessage::Ack { request_id, seq }, ctx),
+                            Err(err) if err.is_violation() && act.violation(ctx) => {},
+                            Err(err) => act.send(ServerMessage::Error { code: ErrorCode::from(&err), request_id, message: err.to_string() }, ctx),
+                        }
+                    })
+                    .spawn(ctx); // do not hold session mailbox while placement is pending
+            },
+            ClientMessage::Resume { from_seq } => {
+                self.place_server.send(ResumeMessage { uuid: self.uuid.clone(), from_seq })
+                    .into_actor(self)
+                    .map(|res, act, ctx| match res {
+                        // missed updates, or full canvas reload if too old
+                        Ok(Some(updates)) => if !updates.is_empty() {
+                            act.send(ServerMessage::PixelUpdates(updates.into()), ctx);
+                        },
+                        Ok(None) => act.send(ServerMessage::Reload, ctx),
+                        Err(err) => log::error!("Failed to resume session updates: {err}"),
+                    })
+                    .spawn(ctx);
+            },
+            ClientMessage::Subscribe(chunks) => self.place_server.do_send(SubscribeMessage { uuid: self.uuid.clone(), chunks, subscribe: true }),
+            ClientMessage::Unsubscribe(chunks) => self.place_server.do_send(SubscribeMessage { uuid: self.uuid.clone(), chunks, subscribe: false }),
+        }
     }
 }
 
@@ -45,13 +193,18 @@ impl Actor for PlaceSession {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        self.place_server.do_send(ConnectMessage {
-            uuid: self.uuid.clone(),
-            addr: ctx.address()
+        ctx.run_later(HANDSHAKE_TIMEOUT, |act, ctx| {
+            if !act.handshake_done {
+                act.invalid_message("Handshake timeout", ctx);
+            }
         });
     }
 
     fn stopping(&mut self, _ctx: &mut Self::Context) -> Running {
+        if !self.handshake_done {
+            return Running::Stop;
+        }
+
         self.place_server.do_send(DisconnectMessage {
             uuid: self.uuid.clone(),
             close_reason: self.close_reason.clone(),
@@ -65,25 +218,49 @@ impl Handler<OnlineUserCountMessage> for PlaceSession {
     type Result = ();
 
     fn handle(&mut self, msg: OnlineUserCountMessage, ctx: &mut Self::Context) -> Self::Result {
-        let mut session = self.session.clone();
-        async move {
-            session.text(format!("/count {}", msg.0)).await.ok();
-        }
-        .into_actor(self)
-        .wait(ctx);
+        self.send(ServerMessage::OnlineCount(msg.0), ctx);
     }
 }
 
-impl Handler<model::PixelColorUpdateMessage> for PlaceSession {
+impl Handler<CooldownMessage> for PlaceSession {
     type Result = ();
 
-    fn handle(&mut self, msg: model::PixelColorUpdateMessage, ctx: &mut Self::Context) -> Self::Result {
-        let mut session = self.session.clone();
-        async move {
-            session.binary(msg.serialize()).await.ok();
-        }
-        .into_actor(self)
-        .wait(ctx);
+    fn handle(&mut self, msg: CooldownMessage, ctx: &mut Self::Context) -> Self::Result {
+        self.cooldown(msg.0, ctx);
+    }
+}
+
+impl Handler<ConfigChangedMessage> for PlaceSession {
+    type Result = ();
+
+    fn handle(&mut self, _msg: ConfigChangedMessage, ctx: &mut Self::Context) -> Self::Result {
+        self.send(ServerMessage::ConfigChanged, ctx);
+        // pixel amount and timeout may have changed
+        self.cooldown_request(ctx);
+    }
+}
+
+impl Handler<PixelUpdatesMessage> for PlaceSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: PixelUpdatesMessage, ctx: &mut Self::Context) -> Self::Result {
+        self.send(ServerMessage::PixelUpdates(msg.0), ctx);
+    }
+}
+
+impl Handler<ReloadMessage> for PlaceSession {
+    type Result = ();
+
+    fn handle(&mut self, _msg: ReloadMessage, ctx: &mut Self::Context) -> Self::Result {
+        self.send(ServerMessage::Reload, ctx);
+    }
+}
+
+impl Handler<SyncedMessage> for PlaceSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: SyncedMessage, ctx: &mut Self::Context) -> Self::Result {
+        self.send(ServerMessage::Synced(msg.0), ctx);
     }
 }
 
@@ -91,31 +268,20 @@ impl Handler<WsMessage> for PlaceSession {
     type Result = ();
 
     fn handle(&mut self, msg: WsMessage, ctx: &mut Self::Context) -> Self::Result {
-        let msg: ws::Message = msg.0;
-        let mut session = self.session.clone();
-        let place_server = self.place_server.clone();
-        let uuid = self.uuid.clone();
-
-        async move {
-            let close_reason = match msg {
-                ws::Message::Ping(msg) => { session.pong(&msg).await.ok(); None },
-                ws::Message::Text(text) => { session.text(text).await.ok(); None },
-                ws::Message::Binary(bin) => { place_server.send(UserPixelColorMessage::new(uuid, &bin).unwrap()).await.ok(); None },
-                ws::Message::Close(reason) => {
-                    Some(reason)
-                },
-                _ => { None },
-            };
-            close_reason
+        match msg.0 {
+            ws::Message::Ping(bytes) => {
+                let mut session = self.session.clone();
+                async move {
+                    session.pong(&bytes).await.ok();
+                }
+                .into_actor(self)
+                .wait(ctx);
+            },
+            ws::Message::Binary(bin) => self.client_message(&bin, ctx),
+            ws::Message::Text(_) => self.invalid_message("Text frames are not supported", ctx),
+            ws::Message::Close(reason) => self.close(reason, ctx),
+            _ => {},
         }
-        .into_actor(self) // converts future to ActorFuture
-        .then(|res, act, ctx| {
-            if let Some(opt_reason) = res {
-                act.close(opt_reason, ctx);
-            }
-            fut::ready(())
-        })
-        .wait(ctx);
     }
 }
 
@@ -0,0 +1,348 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use base64::prelude::*;
+use clap::{Args, Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+
+use crate::config_loader::ConfigSources;
+use crate::controller::CanvasInfoResponse;
+use crate::model::{self, Client, ConfigColor, QuotaMode, SharedConfig, StorageKind};
+use crate::render::{self, Region};
+use crate::store::{self, CanvasChunk, CanvasChunks, CanvasLayout, CanvasMigration};
+use crate::timelapse::{self, TimelapseFormat, TimelapseOptions};
+
+const CONFIG_PATH_DEFAULT: &str = "../config.json";
+
+const TIMELAPSE_INTERVAL_DEFAULT: u64 = 60;
+const TIMELAPSE_FRAME_DELAY_DEFAULT: u16 = 100;
+const TIMELAPSE_SCALE_DEFAULT: usize = 1;
+
+/// Pixel wars backend
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Config file
+    #[arg(short, long, global = true, default_value = CONFIG_PATH_DEFAULT)]
+    pub config: PathBuf,
+    #[command(flatten)]
+    pub overrides: ConfigOverrides,
+    /// Command to run, `serve` if none
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Config values taking precedence over the config file and environment variables, serialized as config fields
+#[derive(Debug, Default, Args, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfigOverrides {
+    /// redis, memory or embedded
+    #[arg(long, global = true)]
+    pub storage: Option<StorageKind>,
+    #[arg(long, global = true)]
+    pub redis_url: Option<String>,
+    /// Relative to the working directory
+    #[arg(long, global = true)]
+    pub embedded_path: Option<PathBuf>,
+    #[arg(long, global = true)]
+    pub host: Option<String>,
+    #[arg(long, global = true)]
+    pub port: Option<u16>,
+    #[arg(long, global = true)]
+    pub base_pixel_amount: Option<usize>,
+    /// In seconds
+    #[arg(long, global = true)]
+    pub timeout: Option<u64>,
+    /// refillAll or tokenBucket
+    #[arg(long, global = true)]
+    pub quota_mode: Option<QuotaMode>,
+    /// In seconds, 0 to disable
+    #[arg(long, global = true)]
+    pub snapshot_interval: Option<u64>,
+    /// In milliseconds, 0 to send updates right away
+    #[arg(long, global = true)]
+    pub tick_interval: Option<u64>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the HTTP and websocket server
+    Serve,
+    /// Check the config and report every invalid field
+    CheckConfig,
+    /// Print the canvas as it was at a unix timestamp, same JSON as `/api/canvas?at=`
+    CanvasAt {
+        at: u64,
+    },
+    /// Write the canvas to a JSON file, independent of chunk layout
+    Export {
+        output: PathBuf,
+        /// Export the canvas as it was at this unix timestamp
+        #[arg(long)]
+        at: Option<u64>,
+    },
+    /// Replace the canvas with an exported one, colors are matched with the active palette
+    Import {
+        input: PathBuf,
+    },
+    /// Save a canvas snapshot for time travel
+    Snapshot,
+    /// Replace the canvas with the canvas as it was at a unix timestamp
+    Restore {
+        at: u64,
+    },
+    /// Convert the stored canvas to the current config after changing canvas size, chunk size or palette
+    Migrate {
+        /// Config the canvas was stored with, read without environment variables and flags
+        #[arg(long)]
+        from: PathBuf,
+    },
+    /// Render placement history into an animation
+    Timelapse(TimelapseArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct TimelapseArgs {
+    /// Output file, or directory for frames
+    output: PathBuf,
+    /// gif, apng or frames, guessed from the output extension by default
+    #[arg(long)]
+    format: Option<TimelapseFormat>,
+    /// Canvas time between two frames, in seconds
+    #[arg(long, default_value_t = TIMELAPSE_INTERVAL_DEFAULT)]
+    interval: u64,
+    /// Display duration of a frame, in milliseconds
+    #[arg(long, default_value_t = TIMELAPSE_FRAME_DELAY_DEFAULT)]
+    delay: u16,
+    /// Canvas part to render as x,y,w,h
+    #[arg(long)]
+    region: Option<Region>,
+    /// Size of a canvas pixel in the output
+    #[arg(long, default_value_t = TIMELAPSE_SCALE_DEFAULT)]
+    scale: usize,
+}
+
+impl From<TimelapseArgs> for TimelapseOptions {
+    fn from(args: TimelapseArgs) -> Self {
+        // guess format from output extension
+        let format = args.format.unwrap_or(match args.output.extension().and_then(|ext| ext.to_str()) {
+            Some("gif") => TimelapseFormat::Gif,
+            Some("png") | Some("apng") => TimelapseFormat::Apng,
+            _ => TimelapseFormat::Frames,
+        });
+
+        TimelapseOptions {
+            format,
+            output: args.output,
+            interval: args.interval,
+            frame_delay: args.delay,
+            region: args.region,
+            scale: args.scale,
+        }
+    }
+}
+
+/// Print every config value with the layer it comes from, then whether the config is valid
+pub fn check_config(config: &model::Config, sources: &ConfigSources) -> Result<(), Box<dyn Error>> {
+    println!("{sources}");
+    match config.validate() {
+        Ok(()) => {
+            println!("Config is valid");
+            Ok(())
+        },
+        Err(errors) => {
+            println!("{errors}");
+            Err(format!("{} invalid config fields", errors.0.len()).into())
+        },
+    }
+}
+
+/// Print the canvas as it was at timestamp, same JSON as `/api/canvas?at=`
+pub async fn canvas_at(config: &model::Config, at: u64) -> Result<(), Box<dyn Error>> {
+    let store = store::open_store(&config.clone().into()).await?;
+    let canvas_chunks = store.canvas_get_at(at).await?;
+
+    println!("{}", serde_json::to_string(&CanvasInfoResponse::new(config, canvas_chunks))?);
+    Ok(())
+}
+
+/// Canvas file written by `export`, one color index per pixel so it does not depend on chunk layout
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CanvasExport {
+    timestamp: u64,
+    width: usize,
+    height: usize,
+    /// Palette indexed by pixels
+    colors: Vec<ConfigColor>,
+    /// Color index of every pixel row by row, base64 encoded
+    pixels: String,
+}
+
+impl CanvasExport {
+    fn new(config: &model::Config, chunks: &CanvasChunks, timestamp: u64) -> Self {
+        Self {
+            timestamp,
+            width: config.canvas_width,
+            height: config.canvas_height,
+            colors: config.active_colors(),
+            pixels: BASE64_STANDARD.encode(render::canvas_color_indices(config, chunks)),
+        }
+    }
+
+    /// Chunks in the layout of config, colors matched with its active palette
+    fn to_chunks(&self, config: &model::Config) -> Result<CanvasChunks, Box<dyn Error>> {
+        let pixels = BASE64_STANDARD.decode(&self.pixels)?;
+        if pixels.len() != self.width * self.height {
+            return Err(format!("Canvas has {} pixels, expected {}x{}", pixels.len(), self.width, self.height).into());
+        }
+
+        let active_colors = config.active_colors();
+        let color_map = self.colors.iter()
+            .map(|color| active_colors.iter().position(|active_color| active_color == color))
+            .collect::<Vec<_>>();
+
+        let mut chunks = CanvasChunk::canvas_empty(config);
+        let mut cropped = 0;
+        for (index, color_index) in pixels.iter().enumerate() {
+            let (pos_x, pos_y) = (index % self.width, index / self.width);
+            if pos_x >= config.canvas_width || pos_y >= config.canvas_height {
+                cropped += 1;
+                continue;
+            }
+
+            let color = match color_map.get(usize::from(*color_index)) {
+                Some(Some(color)) => *color,
+                Some(None) => return Err(format!("Color {:?} is not in the active palette", self.colors[usize::from(*color_index)]).into()),
+                None => return Err(format!("Pixel {pos_x},{pos_y} has unknown color {color_index}").into()),
+            };
+            CanvasChunk::canvas_pixel_write(config, &mut chunks, pos_x, pos_y, color as model::PixelColorUpdateMessageColor);
+        }
+
+        if cropped > 0 {
+            log::warn!("{cropped} pixels outside of the {}x{} canvas were dropped", config.canvas_width, config.canvas_height);
+        }
+        Ok(chunks)
+    }
+}
+
+/// Write the current canvas, or the canvas at timestamp, to a file
+pub async fn export(config: &model::Config, output: &Path, at: Option<u64>) -> Result<(), Box<dyn Error>> {
+    let store = store::open_store(&config.clone().into()).await?;
+    let (timestamp, chunks) = match at {
+        Some(at) => (at, store.canvas_get_at(at).await?),
+        None => (Client::timestamp_now(), store.canvas_get().await?),
+    };
+
+    serde_json::to_writer(BufWriter::new(File::create(output)?), &CanvasExport::new(config, &chunks, timestamp))?;
+    log::info!("Exported canvas at {} to {}", timestamp, output.display());
+    Ok(())
+}
+
+/// Replace the canvas with an exported file
+pub async fn import(config: &model::Config, input: &Path) -> Result<(), Box<dyn Error>> {
+    let canvas_export: CanvasExport = serde_json::from_reader(BufReader::new(File::open(input)?))?;
+    let chunks = canvas_export.to_chunks(config)?;
+
+    let store = store::open_store(&config.clone().into()).await?;
+    store.canvas_set(&chunks).await?;
+    // time travel starts again from the imported canvas
+    let snapshot = store.snapshot_take().await?;
+
+    log::info!("Imported canvas from {}, snapshot saved at {}", input.display(), snapshot.timestamp);
+    Ok(())
+}
+
+/// Save a canvas snapshot for time travel
+pub async fn snapshot(config: &model::Config) -> Result<(), Box<dyn Error>> {
+    let store = store::open_store(&config.clone().into()).await?;
+    let snapshot = store.snapshot_take().await?;
+
+    log::info!("Saved canvas snapshot at {}", snapshot.timestamp);
+    Ok(())
+}
+
+/// Replace the canvas with the canvas as it was at timestamp
+pub async fn restore(config: &model::Config, at: u64) -> Result<(), Box<dyn Error>> {
+    let store = store::open_store(&config.clone().into()).await?;
+    let chunks = store.canvas_get_at(at).await?;
+    store.canvas_set(&chunks).await?;
+    let snapshot = store.snapshot_take().await?;
+
+    log::info!("Restored canvas as it was at {}, snapshot saved at {}", at, snapshot.timestamp);
+    Ok(())
+}
+
+/// Read the canvas stored with the previous config and write it back with the current one
+pub async fn migrate(config: &model::Config, previous_config: &model::Config) -> Result<(), Box<dyn Error>> {
+    if config.storage == StorageKind::Memory {
+        return Err("Nothing to migrate with memory storage".into());
+    }
+
+    // previous store is closed before opening the current one, embedded storage file can only be opened once
+    let canvas_export = {
+        let shared_previous_config: SharedConfig = previous_config.clone().into();
+        let previous_store = store::open_store_unchecked(&shared_previous_config).await?;
+
+        // read chunks with the bits they were stored with, legacy chunks have 4 bits whatever the palette
+        let mut read_config = previous_config.clone();
+        if let Some(stored_layout) = previous_store.layout_stored().await? {
+            if stored_layout.chunk_size != previous_config.canvas_chunk_size {
+                return Err(format!("Canvas is stored with {stored_layout}, previous config has chunks of {}", previous_config.canvas_chunk_size).into());
+            }
+            read_config.stored_bits_per_pixel = Some(stored_layout.bits_per_pixel);
+            shared_previous_config.set(read_config.clone());
+        }
+        CanvasExport::new(&read_config, &previous_store.canvas_get().await?, Client::timestamp_now())
+    };
+    let chunks = canvas_export.to_chunks(config)?;
+
+    let store = store::open_store_unchecked(&config.clone().into()).await?;
+    store.canvas_set(&chunks).await?;
+    store.layout_set(&CanvasLayout::new(config)).await?;
+    let snapshot = store.snapshot_take().await?;
+    // history before this snapshot was placed with the previous layout
+    store.migration_set(&CanvasMigration { timestamp: snapshot.timestamp, layout: snapshot.layout }).await?;
+
+    log::info!("Migrated canvas to {} bits per pixel and chunks of {}, snapshot saved at {}", config.bits_per_pixel(), config.canvas_chunk_size, snapshot.timestamp);
+    Ok(())
+}
+
+/// Render placement history into an animation
+pub async fn timelapse(config: &model::Config, options: &TimelapseOptions) -> Result<(), Box<dyn Error>> {
+    let store = store::open_store(&config.clone().into()).await?;
+    let frame_count = timelapse::timelapse(store.as_ref(), options).await?;
+
+    log::info!("Wrote timelapse of {} frames to {}", frame_count, options.output.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{PixelColorUpdateMessage, PixelHistoryEntry};
+    use crate::test_util::TempPath;
+
+    #[tokio::test]
+    async fn time_travel_refused_before_migration_without_snapshot() {
+        // declared first, stores are closed before the file is removed
+        let path = TempPath::new("cli-migrate-test.redb");
+        let previous_config = model::Config { storage: StorageKind::Embedded, embedded_path: path.path().to_path_buf(), ..model::Config::test(4) };
+        let config = model::Config { colors: model::Config::test(32).colors, ..previous_config.clone() };
+
+        {
+            let previous_store = store::open_store(&previous_config.clone().into()).await.unwrap();
+            let pixel_update = PixelColorUpdateMessage { pos_x: 1, pos_y: 1, color: 3 };
+            previous_store.pixel_set(&pixel_update).await.unwrap();
+            previous_store.history_append(&PixelHistoryEntry::new("author", &pixel_update, 100)).await.unwrap();
+        }
+        migrate(&config, &previous_config).await.unwrap();
+
+        let store = store::open_store(&config.into()).await.unwrap();
+        // history before the migration holds 2 bits colors, replaying it into 8 bits chunks would be garbage
+        assert!(store.canvas_get_at(100).await.is_err());
+        assert!(store.canvas_get_at(u64::MAX).await.is_ok());
+    }
+}
@@ -0,0 +1,348 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::model::Config;
+
+/// Prefix of environment variables overriding config fields, `PLACE_CANVAS_WIDTH` sets `canvasWidth`
+const ENV_PREFIX: &str = "PLACE_";
+
+/// Environment variables kept from before the prefixed ones, prefixed variables win over them
+const ENV_LEGACY: &[(&str, &str)] = &[
+    ("REDIS_URL", "redisUrl"),
+    ("HOST", "host"),
+    ("PORT", "port"),
+];
+
+/// How an environment variable value is read
+#[derive(Debug, Clone, Copy)]
+enum EnvValue {
+    /// Taken as is
+    Text,
+    /// Number, boolean or object, parsed as JSON
+    Json,
+    /// JSON array, brackets may be left out as in `0,1,2`
+    List,
+}
+
+/// Every config field, in config file order
+const CONFIG_FIELDS: &[(&str, EnvValue)] = &[
+    ("storage", EnvValue::Text),
+    ("redisUrl", EnvValue::Text),
+    ("embeddedPath", EnvValue::Text),
+    ("host", EnvValue::Text),
+    ("port", EnvValue::Json),
+    ("canvasWidth", EnvValue::Json),
+    ("canvasHeight", EnvValue::Json),
+    ("canvasChunkSize", EnvValue::Json),
+    ("basePixelAmount", EnvValue::Json),
+    ("timeout", EnvValue::Json),
+    ("quotaMode", EnvValue::Text),
+    ("snapshotInterval", EnvValue::Json),
+    ("tickInterval", EnvValue::Json),
+    ("colors", EnvValue::List),
+    ("colorsActive", EnvValue::List),
+    ("features", EnvValue::Json),
+];
+
+/// Layer a config value comes from, later layers take precedence
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    Default,
+    File(PathBuf),
+    /// Environment variable name
+    Env(String),
+    /// Command line flag
+    Flag(String),
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Default => write!(f, "default"),
+            Self::File(path) => write!(f, "file {}", path.display()),
+            Self::Env(name) => write!(f, "env {name}"),
+            Self::Flag(flag) => write!(f, "flag {flag}"),
+        }
+    }
+}
+
+/// Source and raw value of every config field, unset fields keep their default
+#[derive(Debug, Clone, Default)]
+pub struct ConfigSources(BTreeMap<&'static str, (ConfigSource, Value)>);
+
+impl ConfigSources {
+    pub fn source(&self, field: &str) -> &ConfigSource {
+        self.0.get(field).map_or(&ConfigSource::Default, |(source, _)| source)
+    }
+
+    /// Fields set by environment variables or flags
+    pub fn overridden(&self) -> impl Iterator<Item = (&'static str, &ConfigSource)> {
+        self.0.iter()
+            .filter(|(_, (source, _))| matches!(source, ConfigSource::Env(_) | ConfigSource::Flag(_)))
+            .map(|(field, (source, _))| (*field, source))
+    }
+
+    fn set(&mut self, field: &'static str, source: ConfigSource, value: Value) {
+        self.0.insert(field, (source, value));
+    }
+}
+
+impl fmt::Display for ConfigSources {
+    /// One field per line, with its value unless it is a default
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, (field, _)) in CONFIG_FIELDS.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            match self.0.get(field) {
+                Some((source, value)) => write!(f, "{field} = {value} ({source})")?,
+                None => write!(f, "{field} ({})", ConfigSource::Default)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Load config from defaults, then file, then `PLACE_*` environment variables, then flags
+///
+/// Flags are any serializable struct with optional camelCase fields, unset ones being skipped.
+pub fn load<F: Serialize>(path: &Path, flags: &F) -> Result<(Config, ConfigSources), Box<dyn Error>> {
+    // variables which are not unicode cannot be config values
+    let vars = env::vars_os().filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)));
+    load_with_vars(path, flags, vars.collect())
+}
+
+/// Load config as `load` does, with environment variables taken from vars
+fn load_with_vars<F: Serialize>(path: &Path, flags: &F, vars: BTreeMap<String, String>) -> Result<(Config, ConfigSources), Box<dyn Error>> {
+    let mut sources = ConfigSources::default();
+    env_layer(&mut sources, &vars)?;
+    for (field, value) in flag_layer(flags)? {
+        sources.set(field, ConfigSource::Flag(format!("--{}", kebab_case(field))), value);
+    }
+
+    load_layers(path, sources)
+}
+
+/// Load config from defaults and file only
+pub fn load_file(path: &Path) -> Result<Config, Box<dyn Error>> {
+    load_layers(path, ConfigSources::default()).map(|(config, _)| config)
+}
+
+/// Merge file fields under overrides and deserialize the result
+fn load_layers(path: &Path, overrides: ConfigSources) -> Result<(Config, ConfigSources), Box<dyn Error>> {
+    let mut sources = ConfigSources::default();
+    for (field, value) in file_layer(path)? {
+        sources.set(field, ConfigSource::File(path.to_path_buf()), value);
+    }
+    sources.0.extend(overrides.0);
+
+    let merged = sources.0.iter()
+        .map(|(field, (_, value))| (field.to_string(), value.clone()))
+        .collect::<Map<_, _>>();
+    let mut config: Config = serde_path_to_error::deserialize(Value::Object(merged)).map_err(|err| {
+        let path = err.path().to_string();
+        let field = path.split(['[', '.']).next().unwrap_or_default();
+        format!("{path} ({}): {}", sources.source(field), err.inner())
+    })?;
+
+    // keep embedded storage next to config file, overrides are relative to the working directory
+    if config.embedded_path.is_relative() && matches!(sources.source("embeddedPath"), ConfigSource::Default | ConfigSource::File(_)) {
+        if let Some(config_dir) = path.parent() {
+            config.embedded_path = config_dir.join(&config.embedded_path);
+        }
+    }
+
+    Ok((config, sources))
+}
+
+/// Fields of a JSON or TOML file, depending on its extension, fails on unknown fields
+fn file_layer(path: &Path) -> Result<Vec<(&'static str, Value)>, Box<dyn Error>> {
+    let content = fs::read_to_string(path)?;
+    let value: Value = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str(&content)?,
+        _ => serde_json::from_str(&content)?,
+    };
+
+    let Value::Object(mut fields) = value else {
+        return Err("Config file must contain an object".into());
+    };
+    let known_fields = CONFIG_FIELDS.iter()
+        .filter_map(|(field, _)| fields.remove(*field).map(|value| (*field, value)))
+        .collect();
+
+    if let Some(name) = fields.keys().next() {
+        return Err(format!("{name} ({}): unknown field, it does not match any config field", ConfigSource::File(path.to_path_buf())).into());
+    }
+
+    Ok(known_fields)
+}
+
+/// Set fields from environment variables
+fn env_layer(sources: &mut ConfigSources, vars: &BTreeMap<String, String>) -> Result<(), Box<dyn Error>> {
+    for (field, kind) in CONFIG_FIELDS {
+        let name = env_name(field);
+        let legacy_name = ENV_LEGACY.iter().find(|(_, legacy_field)| legacy_field == field).map(|(legacy_name, _)| *legacy_name);
+
+        let Some((name, raw)) = [Some(name.as_str()), legacy_name].into_iter().flatten()
+            .find_map(|name| vars.get(name).map(|raw| (name.to_string(), raw))) else {
+            continue;
+        };
+        let value = env_value(*kind, raw).map_err(|err| format!("{name}: invalid value {raw:?}, {err}"))?;
+        sources.set(field, ConfigSource::Env(name), value);
+    }
+
+    for name in vars.keys() {
+        if name.starts_with(ENV_PREFIX) && !CONFIG_FIELDS.iter().any(|(field, _)| env_name(field) == *name) {
+            log::warn!("Ignoring {name}, it does not match any config field");
+        }
+    }
+
+    Ok(())
+}
+
+fn env_value(kind: EnvValue, raw: &str) -> Result<Value, serde_json::Error> {
+    match kind {
+        EnvValue::Text => Ok(Value::String(raw.to_string())),
+        EnvValue::Json => serde_json::from_str(raw),
+        EnvValue::List => match serde_json::from_str(raw) {
+            Ok(value @ Value::Array(_)) => Ok(value),
+            _ => serde_json::from_str(&format!("[{raw}]")),
+        },
+    }
+}
+
+/// Known fields of the flags that are set
+fn flag_layer<F: Serialize>(flags: &F) -> Result<Vec<(&'static str, Value)>, Box<dyn Error>> {
+    let Value::Object(mut fields) = serde_json::to_value(flags)? else {
+        return Ok(Vec::new());
+    };
+    Ok(CONFIG_FIELDS.iter()
+        .filter_map(|(field, _)| fields.remove(*field).filter(|value| !value.is_null()).map(|value| (*field, value)))
+        .collect())
+}
+
+/// `canvasWidth` to `PLACE_CANVAS_WIDTH`
+fn env_name(field: &str) -> String {
+    format!("{ENV_PREFIX}{}", kebab_case(field).replace('-', "_").to_uppercase())
+}
+
+/// `canvasWidth` to `canvas-width`
+fn kebab_case(field: &str) -> String {
+    let mut result = String::with_capacity(field.len() + 4);
+    for char in field.chars() {
+        if char.is_ascii_uppercase() {
+            result.push('-');
+        }
+        result.push(char.to_ascii_lowercase());
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use uuid::Uuid;
+
+    /// Config file in the temporary directory, removed when dropped
+    struct ConfigFile(PathBuf);
+
+    impl ConfigFile {
+        fn new(extension: &str, content: &str) -> Self {
+            let path = env::temp_dir().join(format!("place-config-{}.{extension}", Uuid::new_v4()));
+            fs::write(&path, content).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for ConfigFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    const CONFIG_JSON: &str = r#"{"host": "localhost", "canvasWidth": 100, "basePixelAmount": 3, "timeout": 10, "colors": [[0, 0, 0]]}"#;
+
+    fn vars(vars: &[(&str, &str)]) -> BTreeMap<String, String> {
+        vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn layers_take_precedence_in_order() {
+        let file = ConfigFile::new("json", CONFIG_JSON);
+        let vars = vars(&[("PLACE_TIMEOUT", "20"), ("PLACE_CANVAS_WIDTH", "200")]);
+        let flags = serde_json::json!({ "canvasWidth": 300, "canvasHeight": null });
+
+        let (config, sources) = load_with_vars(&file.0, &flags, vars).unwrap();
+
+        assert_eq!(config.canvas_height, 256);
+        assert_eq!(sources.source("canvasHeight"), &ConfigSource::Default);
+        assert_eq!(config.base_pixel_amount, 3);
+        assert_eq!(sources.source("basePixelAmount"), &ConfigSource::File(file.0.clone()));
+        assert_eq!(config.timeout.as_secs(), 20);
+        assert_eq!(sources.source("timeout"), &ConfigSource::Env("PLACE_TIMEOUT".to_string()));
+        assert_eq!(config.canvas_width, 300);
+        assert_eq!(sources.source("canvasWidth"), &ConfigSource::Flag("--canvas-width".to_string()));
+
+        let overridden: Vec<_> = sources.overridden().map(|(field, _)| field).collect();
+        assert_eq!(overridden, vec!["canvasWidth", "timeout"]);
+    }
+
+    #[test]
+    fn toml_file_with_relative_embedded_path() {
+        let file = ConfigFile::new("toml", "host = \"localhost\"\nbasePixelAmount = 3\ntimeout = 10\ncolors = [[0, 0, 0]]\nembeddedPath = \"data/place.redb\"\n");
+
+        let config = load_file(&file.0).unwrap();
+        assert_eq!(config.timeout.as_secs(), 10);
+        assert_eq!(config.embedded_path, env::temp_dir().join("data/place.redb"));
+    }
+
+    #[test]
+    fn invalid_field_reports_path_and_source() {
+        let file = ConfigFile::new("json", &CONFIG_JSON.replace("[[0, 0, 0]]", "[[0, 0, 256]]"));
+
+        let err = load_file(&file.0).unwrap_err().to_string();
+        assert!(err.starts_with(&format!("colors[0][2] (file {})", file.0.display())), "{err}");
+    }
+
+    #[test]
+    fn unknown_file_field_reports_path_and_source() {
+        let file = ConfigFile::new("json", &CONFIG_JSON.replace("\"timeout\"", "\"timeuot\""));
+
+        let err = load_file(&file.0).unwrap_err().to_string();
+        assert!(err.starts_with(&format!("timeuot (file {}): unknown field", file.0.display())), "{err}");
+    }
+
+    #[test]
+    fn prefixed_env_wins_over_legacy_name() {
+        let mut sources = ConfigSources::default();
+        env_layer(&mut sources, &vars(&[("PORT", "8080"), ("PLACE_PORT", "9090"), ("HOST", "0.0.0.0")])).unwrap();
+        assert_eq!(sources.source("port"), &ConfigSource::Env("PLACE_PORT".to_string()));
+        assert_eq!(sources.source("host"), &ConfigSource::Env("HOST".to_string()));
+
+        let err = env_layer(&mut sources, &vars(&[("PLACE_TIMEOUT", "ten")])).unwrap_err().to_string();
+        assert!(err.starts_with("PLACE_TIMEOUT: invalid value"), "{err}");
+    }
+
+    #[test]
+    fn env_values() {
+        assert_eq!(env_value(EnvValue::Text, "redis://localhost").unwrap(), Value::String("redis://localhost".to_string()));
+        assert_eq!(env_value(EnvValue::Json, "12").unwrap(), serde_json::json!(12));
+        assert_eq!(env_value(EnvValue::List, "0,1,2").unwrap(), serde_json::json!([0, 1, 2]));
+        assert_eq!(env_value(EnvValue::List, "[[0,0,0]]").unwrap(), serde_json::json!([[0, 0, 0]]));
+        assert!(env_value(EnvValue::Json, "twelve").is_err());
+    }
+
+    #[test]
+    fn field_names() {
+        assert_eq!(env_name("canvasWidth"), "PLACE_CANVAS_WIDTH");
+        assert_eq!(kebab_case("colorsActive"), "colors-active");
+    }
+}
@@ -1,148 +1,213 @@
-use std::vec;
-
-use actix_web::{get, web, HttpResponse, Responder};
-use serde::Serialize;
-use crate::model::{self, BackendError, ConfigColor};
-use redis::{AsyncCommands, Commands, RedisError};
+use actix_web::http::header::{CacheControl, CacheDirective, EntityTag, ETag, IfNoneMatch};
+use actix_web::{error, get, web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use crate::model::{self, ConfigColor, Features};
+use crate::render::{CanvasPngCache, ChunkUpdate, Region};
+use crate::store::{CanvasChunks, CanvasStore};
 use base64::prelude::*;
 
-pub struct CanvasChunk;
-impl CanvasChunk {
-    fn chunk_index_to_key(chunk_index_x: usize, chunk_index_y: usize) -> String {
-        let result = format!("{}_{}_{}", model::CANVAS_DB_KEY, chunk_index_x, chunk_index_y);
-        result
-    }
-
-    async fn chunk_create(config: &model::Config, con: &mut impl AsyncCommands, chunk_key: &str) -> Result<Vec<u8>, RedisError> {
-        let vec_size = (config.canvas_chunk_size as usize * config.canvas_chunk_size as usize) / config.pixels_per_bytes;
-        con.setbit(chunk_key, vec_size * 8 - 1, false).await?; // set latest chunk bit (thus *8 - 1) to create empty string with 0 value
-        Ok(vec![0; vec_size])
-    }
-
-    async fn chunk_get(config: &model::Config, con: &mut impl AsyncCommands, chunk_index_x: usize, chunk_index_y: usize) -> Result<Vec<u8>, RedisError> {
-        let chunk_key = Self::chunk_index_to_key(chunk_index_x, chunk_index_y);
-        let opt_colors: Option<Vec<u8>> = con.get(&chunk_key).await?;
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CanvasInfoSize {
+    width: usize,
+    height: usize,
+    chunk_size: usize,
+}
 
-        let mut colors = match opt_colors {
-            Some(colors) => colors,
-            None => Self::chunk_create(config, con, &chunk_key).await?
-        };
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CanvasInfoResponse {
+    canvas: Vec<Vec<String>>, // list of chunks
+    size: CanvasInfoSize,
+    colors: Vec<ConfigColor>,
+    /// Bits of each pixel color in chunks, most significant bit first
+    bits_per_pixel: usize,
+    features: Features,
+    /// Latest placement included, websocket updates can be resumed from it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+}
 
-        colors.truncate((config.canvas_chunk_size as usize * config.canvas_chunk_size as usize) / config.pixels_per_bytes);
-        Ok(colors)
+impl CanvasInfoResponse {
+    pub fn new(config: &model::Config, canvas_chunks: CanvasChunks) -> Self {
+        let encoded_chunks = canvas_chunks.into_iter().map(|chunk_row|
+            chunk_row.into_iter().map(|chunk| BASE64_STANDARD.encode(&chunk)).collect::<Vec<_>>()
+        ).collect::<Vec<_>>();
+
+        CanvasInfoResponse {
+            canvas: encoded_chunks,
+            size: CanvasInfoSize {
+                width: config.canvas_width,
+                height: config.canvas_height,
+                chunk_size: config.canvas_chunk_size,
+            },
+            colors: config.active_colors(),
+            bits_per_pixel: config.bits_per_pixel(),
+            features: config.features,
+            seq: None,
+        }
     }
 
-    fn chunk_update(
-        config: &model::Config,
-        con: &mut impl Commands,
-        chunk_loc: model::ChunkLocation,
-        pixel_color: &model::PixelColorUpdateMessageColor
-    ) -> Result<(), RedisError> {
-        let (chunk_index, chunk_pos) = chunk_loc;
-        let (chunk_index_x, chunk_index_y) = chunk_index;
-        let (chunk_pos_x, chunk_pos_y) = chunk_pos;
-
-        let chunk_key = Self::chunk_index_to_key(chunk_index_x, chunk_index_y);
+    pub fn with_seq(mut self, seq: u64) -> Self {
+        self.seq = Some(seq);
+        self
+    }
+}
 
-        // bit offset because multiplied by 4 where 4 = 8 / pixels_per_byte
-        let pixel_bit_width = 8 / config.pixels_per_bytes;
-        let bit_offset = (chunk_pos_y * config.canvas_chunk_size as usize + chunk_pos_x) as usize * pixel_bit_width;
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CanvasMetaResponse {
+    size: CanvasInfoSize,
+    /// Number of chunks along x and y
+    chunks: (usize, usize),
+    colors: Vec<ConfigColor>,
+    /// Bits of each pixel color in chunks, most significant bit first
+    bits_per_pixel: usize,
+    features: Features,
+}
 
-        for i in 0..4usize {
-            let is_bit_one = (pixel_color & (1 << i)) > 0;
+impl CanvasMetaResponse {
+    pub fn new(config: &model::Config) -> Self {
+        CanvasMetaResponse {
+            size: CanvasInfoSize {
+                width: config.canvas_width,
+                height: config.canvas_height,
+                chunk_size: config.canvas_chunk_size,
+            },
+            chunks: config.canvas_chunks(),
+            colors: config.active_colors(),
+            bits_per_pixel: config.bits_per_pixel(),
+            features: config.features,
+        }
+    }
+}
 
-            let redis_offset = bit_offset + (3-i);
+#[derive(Debug, Deserialize)]
+pub struct CanvasQuery {
+    /// Unix timestamp in seconds to get the canvas as it was back then
+    at: Option<u64>,
+}
 
-            con.setbit(&chunk_key, redis_offset, is_bit_one)?;
+#[get("/canvas")]
+pub async fn canvas_get(
+    query: web::Query<CanvasQuery>,
+    store: web::Data<dyn CanvasStore>,
+    config: web::Data<model::SharedConfig>
+) -> actix_web::Result<impl Responder> {
+    let config = config.get();
+    let response = match query.at {
+        Some(_) if !config.features.time_travel => return Err(error::ErrorNotFound("Time travel is disabled")),
+        Some(at) if at < model::Client::timestamp_now() => CanvasInfoResponse::new(&config, store.canvas_get_at(at).await?),
+        _ => {
+            // seq read first, updates placed meanwhile are sent again on resume
+            let seq = store.history_seq().await?;
+            CanvasInfoResponse::new(&config, store.canvas_get().await?).with_seq(seq)
         }
+    };
 
-        Ok(())
-    }
+    Ok(HttpResponse::Ok().json(response))
 }
 
-async fn canvas_redis_get(
-    redis: &redis::Client,
-    config: &model::Config
-) -> Result<Vec<Vec<Vec<u8>>>, RedisError>
-{
-    let mut con = redis.get_multiplexed_async_connection().await?;
+#[get("/canvas/meta")]
+pub async fn canvas_meta_get(config: web::Data<model::SharedConfig>) -> impl Responder {
+    HttpResponse::Ok().json(CanvasMetaResponse::new(&config.get()))
+}
 
-    let chunk_numbers = config.canvas_chunks();
-    let (chunk_rows, chunk_cols) = chunk_numbers;
+/// Raw chunk bytes, same layout as the base64 chunks of `/canvas`
+///
+/// ETag is the store epoch and chunk version, so an unchanged chunk is answered with 304
+#[get("/canvas/chunk/{cx}/{cy}")]
+pub async fn canvas_chunk_get(
+    path: web::Path<(usize, usize)>,
+    if_none_match: Option<web::Header<IfNoneMatch>>,
+    store: web::Data<dyn CanvasStore>,
+    config: web::Data<model::SharedConfig>
+) -> actix_web::Result<impl Responder> {
+    let (chunk_index_x, chunk_index_y) = path.into_inner();
+    let (chunk_rows, chunk_cols) = config.get().canvas_chunks();
+    if chunk_index_x >= chunk_rows || chunk_index_y >= chunk_cols {
+        return Err(error::ErrorBadRequest("Invalid chunk index in canvas"));
+    }
 
-    let mut result = Vec::with_capacity(chunk_rows);
+    // version read first, a write in between only makes the client fetch again
+    let (epoch, version) = store.chunk_version(chunk_index_x, chunk_index_y).await?;
+    let etag = EntityTag::new_strong(format!("{epoch}-{version}"));
+    let cache_control = CacheControl(vec![CacheDirective::NoCache]);
 
-    for index_x in 0..chunk_rows {
-        let mut row_vec = Vec::with_capacity(chunk_cols);
-        for index_y in 0..chunk_cols {
-            row_vec.push(CanvasChunk::chunk_get(config, &mut con, index_x, index_y).await?);
-        }
-        result.push(row_vec);
+    let not_modified = match if_none_match.map(web::Header::into_inner) {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => false
+    };
+    if not_modified {
+        return Ok(HttpResponse::NotModified()
+            .insert_header(ETag(etag))
+            .insert_header(cache_control)
+            .finish());
     }
 
-    return Ok(result);
-}
+    let chunk = store.chunk_get(chunk_index_x, chunk_index_y).await?;
 
-pub fn canvas_redis_set(
-    redis: &redis::Client,
-    config: &model::Config,
-    pixel_update: &model::PixelColorUpdateMessage
-) -> Result<(), String>
-{
-    let chunk_loc = config.canvas_pos_to_chunk_location(pixel_update.pos_x.into(), pixel_update.pos_y.into());
-    let mut con = redis.get_connection()
-        .map_err(|e| e.to_string())?;
-
-    CanvasChunk::chunk_update(config, &mut con, chunk_loc, &pixel_update.color)
-        .map_err(|e| e.to_string())
-}
-
-#[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct CanvasInfoSize {
-    width: usize,
-    height: usize,
-    chunk_size: usize,
+    Ok(HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .insert_header(ETag(etag))
+        .insert_header(cache_control)
+        .body(chunk))
 }
 
-#[derive(Debug, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct CanvasInfoResponse {
-    canvas: Vec<Vec<String>>, // list of chunks
-    size: CanvasInfoSize,
-    colors: Vec<ConfigColor>,
+/// Largest pixel scale of rendered images
+const PNG_SCALE_MAX: usize = 32;
+/// Largest pixel count of rendered images, after scaling
+const PNG_PIXELS_MAX: usize = 4096 * 4096;
+
+#[derive(Debug, Deserialize)]
+pub struct CanvasPngQuery {
+    scale: Option<usize>,
+    x: Option<usize>,
+    y: Option<usize>,
+    w: Option<usize>,
+    h: Option<usize>,
 }
 
-#[get("/canvas")]
-pub async fn canvas_get(
-    redis: web::Data<redis::Client>,
-    config: web::Data<model::Config>
+#[get("/canvas.png")]
+pub async fn canvas_png_get(
+    query: web::Query<CanvasPngQuery>,
+    store: web::Data<dyn CanvasStore>,
+    cache: web::Data<CanvasPngCache>,
+    config: web::Data<model::SharedConfig>
 ) -> actix_web::Result<impl Responder> {
-    let canvas_chunks = canvas_redis_get(&redis, &config).await.map_err(BackendError::from)?;
-
-    let encoded_chunks = canvas_chunks.into_iter().map(|chunk_row|
-        chunk_row.into_iter().map(|chunk| BASE64_STANDARD.encode(&chunk)).collect::<Vec<_>>()
-    ).collect::<Vec<_>>();
-
-    let active_colors =  if let Some(colors_active) = &config.colors_active {
-        let mut filtered_ordered_colors = Vec::with_capacity(colors_active.len());
-        for color_index in colors_active {
-            if let Some(color) = config.colors.get(*color_index) {
-                filtered_ordered_colors.push(*color);
-            }
-        }
-        filtered_ordered_colors
-    } else {
-        config.colors.clone()
-    };
+    let config = config.get();
+    let scale = query.scale.unwrap_or(1);
+    if scale == 0 || scale > PNG_SCALE_MAX {
+        return Err(error::ErrorBadRequest(format!("Invalid scale, must be between 1 and {PNG_SCALE_MAX}")));
+    }
+
+    let region = Region {
+        x: query.x.unwrap_or(0),
+        y: query.y.unwrap_or(0),
+        width: query.w.unwrap_or(config.canvas_width),
+        height: query.h.unwrap_or(config.canvas_height),
+    }.clamp(&config).ok_or(error::ErrorBadRequest("Invalid region in canvas"))?;
+    if region.width * scale * region.height * scale > PNG_PIXELS_MAX {
+        return Err(error::ErrorBadRequest(format!("Image too large, scaled region must have at most {PNG_PIXELS_MAX} pixels")));
+    }
+
+    // versions first, a chunk written in between is only decoded again next time
+    let (epoch, chunk_versions) = store.chunk_versions().await?;
+
+    let mut chunk_updates = Vec::new();
+    for chunk_index in cache.chunks_stale(&config, &epoch, &chunk_versions, region) {
+        let (chunk_index_x, chunk_index_y) = chunk_index;
+        chunk_updates.push(ChunkUpdate {
+            chunk_index,
+            version: chunk_versions[chunk_index_x][chunk_index_y],
+            chunk: store.chunk_get(chunk_index_x, chunk_index_y).await?,
+        });
+    }
+
+    // decoding and encoding hold the cache lock, keep them off the async workers
+    let image = web::block(move || cache.canvas_png(&config, &epoch, chunk_updates, region, scale)).await?
+        .map_err(error::ErrorInternalServerError)?;
 
-    Ok(HttpResponse::Ok().json(CanvasInfoResponse {
-        canvas: encoded_chunks,
-        size: CanvasInfoSize {
-            width: config.canvas_width,
-            height: config.canvas_height,
-            chunk_size: config.canvas_chunk_size,
-        },
-        colors: active_colors
-    }))
+    Ok(HttpResponse::Ok().content_type("image/png").body(image))
 }
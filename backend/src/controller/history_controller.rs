@@ -0,0 +1,38 @@
+use actix_web::{error, get, web, HttpResponse, Responder};
+use serde::Serialize;
+
+use crate::model::{self, PixelColorUpdateMessageColor};
+use crate::store::CanvasStore;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PixelHistoryResponseEntry {
+    color: PixelColorUpdateMessageColor,
+    author: String, // never the session uuid, it is the session secret
+    timestamp: u64,
+}
+
+#[get("/pixel/{x}/{y}/history")]
+pub async fn pixel_history_get(
+    path: web::Path<(u16, u16)>,
+    store: web::Data<dyn CanvasStore>,
+    config: web::Data<model::SharedConfig>
+) -> actix_web::Result<impl Responder> {
+    let config = config.get();
+    if !config.features.history {
+        return Err(error::ErrorNotFound("Pixel history is disabled"));
+    }
+
+    let (pos_x, pos_y) = path.into_inner();
+    if pos_x as usize >= config.canvas_width || pos_y as usize >= config.canvas_height {
+        return Err(error::ErrorBadRequest("Invalid position in canvas"));
+    }
+
+    let entries = store.pixel_history(pos_x, pos_y).await?;
+
+    Ok(HttpResponse::Ok().json(entries.into_iter().map(|entry| PixelHistoryResponseEntry {
+        color: entry.color,
+        author: entry.author(),
+        timestamp: entry.timestamp,
+    }).collect::<Vec<_>>()))
+}
@@ -1,6 +1,9 @@
 mod canvas_controller;
 pub use canvas_controller::*;
 
+mod history_controller;
+pub use history_controller::*;
+
 mod session_controller;
 pub use session_controller::*;
 
@@ -1,23 +1,22 @@
 use actix_web::{cookie::{time::Duration, CookieBuilder, SameSite}, get, http::header, web, HttpRequest, HttpResponse};
-use redis::AsyncCommands;
-use redis::RedisResult;
 use uuid::Uuid;
 
-use crate::model::{self, BackendError, Client, SESSION_COOKIE_NAME};
+use crate::model::{self, Client, SESSION_COOKIE_NAME};
+use crate::store::CanvasStore;
 
 #[get("/session")]
 pub async fn session_get(
     req: HttpRequest,
-    redis: web::Data<redis::Client>,
-    config: web::Data<model::Config>
+    store: web::Data<dyn CanvasStore>,
+    config: web::Data<model::SharedConfig>
 ) -> actix_web::Result<HttpResponse> {
-    let mut con = redis.get_multiplexed_async_connection().await.map_err(BackendError::from)?;
     if let Some(uuid) = req.cookie(SESSION_COOKIE_NAME).map(|u| u.value().to_string()) {
-        let redis_res: RedisResult<String> = con.get(uuid).await;
-        if redis_res.is_ok() {
-            return Ok(HttpResponse::Ok().into());
+        if Uuid::parse_str(&uuid).is_ok() {
+            if let Ok(Some(_)) = store.client_get(&uuid).await {
+                return Ok(HttpResponse::Ok().into());
+            }
         }
-    } // return if already redis entry to cookie uuid
+    } // return if already store entry to cookie uuid, invalid cookies get a new one
 
 
     // create new uuid
@@ -40,11 +39,10 @@ pub async fn session_get(
 
 
     // create client with last seen timestamp
-    let client = Client::new(config.base_pixel_amount);
-    // send client to redis
-    let client_string: String = client.encode_json().map_err(BackendError::from)?;
-    log::info!("Added user UUID={} with value: {:?}", &new_uuid, &client_string);
-    con.set(new_uuid, client_string).await.map_err(BackendError::from)?;
+    let client = Client::new(config.get().base_pixel_amount);
+    // send client to store
+    store.client_set(&new_uuid, &client).await?;
+    log::info!("Added user UUID={} with value: {:?}", &new_uuid, &client);
 
 
     // respond with cookie
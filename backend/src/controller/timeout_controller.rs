@@ -1,10 +1,9 @@
 use actix_web::{error, get, web, HttpRequest, HttpResponse, Responder};
-use redis::AsyncCommands;
 use serde::Serialize;
+use uuid::Uuid;
 
-use crate::model::{self, BackendError, Client};
-
-const COOKIE_NAME: &str = "sessionUUID";
+use crate::model::{self, Client, SESSION_COOKIE_NAME};
+use crate::store::CanvasStore;
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -18,38 +17,27 @@ struct ClientTimeoutResponse {
 #[get("/client/timeout")]
 pub async fn client_timeout(
     req: HttpRequest,
-    redis: web::Data<redis::Client>,
-    config: web::Data<model::Config>
+    store: web::Data<dyn CanvasStore>,
+    config: web::Data<model::SharedConfig>
 ) -> actix_web::Result<impl Responder> {
-    let cookie = req.cookie(COOKIE_NAME).ok_or(error::ErrorBadRequest("No cookie provided"))?;
-    let uuid = cookie.value().to_string();
-
-    let mut con  = redis.get_multiplexed_async_connection().await
-        .map_err(BackendError::from)?;
-
-    let redis_result = con.get::<String, String>(uuid.to_string()).await;
-    let mut client = Client::from_redis(redis_result, config.base_pixel_amount);
-
-    let current_timestamp = model::Client::timestamp_now();
-    let duration_secs = current_timestamp - client.last_timestamp;
-    let timeout_secs = config.timeout.as_secs();
-
-
-    if timeout_secs > 0 && duration_secs >= timeout_secs - 1 {
-        client.remaining_pixels = config.base_pixel_amount;
-        client.last_timestamp = current_timestamp;
+    let config = config.get();
+    let uuid = req.cookie(SESSION_COOKIE_NAME)
+        .ok_or(error::ErrorBadRequest("No cookie provided"))?
+        .value().to_string();
+    // same check as the websocket, store keys are built from the uuid
+    Uuid::parse_str(&uuid).map_err(|_| error::ErrorBadRequest("Invalid cookie provided"))?;
 
-        let client_string = client.encode_json()
-            .map_err(BackendError::from)?;
+    let mut client = store.client_get(&uuid).await?
+        .unwrap_or_else(|| Client::new(config.base_pixel_amount));
 
-        con.set(uuid, client_string).await
-            .map_err(BackendError::from)?;
-    }
+    // refill is only shown, next placement saves it
+    client.refill(&config, Client::timestamp_now());
+    let cooldown = client.cooldown(&config);
 
     Ok(HttpResponse::Ok().json(ClientTimeoutResponse {
-        last_timestamp: client.last_timestamp,
-        remaining_pixels: client.remaining_pixels,
-        timeout: timeout_secs,
-        next_timestamp: client.last_timestamp + timeout_secs,
+        last_timestamp: cooldown.last_timestamp,
+        remaining_pixels: cooldown.remaining_pixels,
+        timeout: config.timeout.as_secs(),
+        next_timestamp: cooldown.next_timestamp,
     }))
 }
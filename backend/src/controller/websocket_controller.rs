@@ -1,26 +1,25 @@
 use tokio::task::spawn_local;
 use actix::Addr;
 use actix_web::{error, web, HttpRequest, HttpResponse, get};
-use redis::AsyncCommands;
-
-use crate::{model::{BackendError, SESSION_COOKIE_NAME}, actors::{handler::handle_ws, PlaceServer}};
+use uuid::Uuid;
+use crate::{model::SESSION_COOKIE_NAME, actors::{handler::handle_ws, PlaceServer}, store::CanvasStore};
 
 #[get("/websocket")]
 pub async fn websocket_start(
     req: HttpRequest,
     body: web::Payload,
-    redis: web::Data<redis::Client>,
+    store: web::Data<dyn CanvasStore>,
     server: web::Data<Addr<PlaceServer>>,
 ) -> actix_web::Result<HttpResponse> {
     let uuid = req.cookie(SESSION_COOKIE_NAME)
         .ok_or(error::ErrorBadRequest("No cookie provided"))?
         .value().to_string();
+    // uuid becomes part of store keys, anything else could address other keys
+    Uuid::parse_str(&uuid).map_err(|_| error::ErrorBadRequest("Invalid cookie provided"))?;
 
-    let mut con = redis.get_multiplexed_async_connection().await
-        .map_err(BackendError::from)?;
-
-    con.exists::<_,()>(&uuid).await
-        .map_err(BackendError::from)?;
+    // sessions are created by /api/session, invalid cookies get a new one there
+    store.client_get(&uuid).await?
+        .ok_or(error::ErrorUnauthorized("Unknown session"))?;
 
     let (response, session, msg_stream) = actix_ws::handle(&req, body)?;
 
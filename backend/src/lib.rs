@@ -3,3 +3,11 @@ pub mod model;
 pub mod controller;
 pub mod dev;
 pub mod actors;
+pub mod store;
+pub mod cli;
+pub mod config_loader;
+pub mod render;
+pub mod timelapse;
+pub mod protocol;
+#[cfg(test)]
+mod test_util;
@@ -1,57 +1,74 @@
-use std::path::Path;
-use std::env;
-
-use redis;
-
 use actix::Actor;
 use actix_web::{web, App, HttpServer};
 use actix_cors::Cors;
 use actix_files as fs;
+use clap::Parser;
+use std::path::PathBuf;
 
 
-use backend::{actors, dev, model, routes::routes};
+use backend::{actors, cli::{self, Cli, Command}, config_loader, dev, model, render, routes::routes, store};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
-
-    // TODO: extract args better
-    let mut args = std::env::args();
-    let config_path_string = args.nth(1).unwrap_or("../config.json".into());
-
-    let config_path = Path::new(&config_path_string);
-    let absolute_path = std::path::absolute(config_path)
+    let cli = Cli::parse();
+    let absolute_path = std::path::absolute(&cli.config)
         .map_err(|e| format!("Failed to create absolute path : {e}")).unwrap();
 
     // canvas config
     log::info!("Opening config file located at {}...", absolute_path.display());
-    let config = {
-        let mut config = model::Config::from_file(absolute_path).expect("config.json file not found");
-        // overwrite with env
-        if let Ok(env_var) = env::var("REDIS_URL") {
-            config.redis_url = env_var;
-        }
-        if let Ok(env_var) = env::var("HOST") {
-            config.host = env_var;
+    let (config, sources) = config_loader::load(&absolute_path, &cli.overrides).unwrap_or_else(|err| exit_with_error(format!("Failed to load config: {err}")));
+    for (field, source) in sources.overridden() {
+        log::info!("Config {field} set by {source}");
+    }
+
+    let command = cli.command.unwrap_or(Command::Serve);
+    if let (false, Err(errors)) = (matches!(command, Command::CheckConfig), config.validate()) {
+        for error in &errors.0 {
+            log::error!("Invalid config {error}");
         }
-        if let Ok(env_var) = env::var("PORT") {
-            config.port = env_var.parse().expect("Failed to extract PORT from ENV");
-        }
-        config
+        std::process::exit(1);
+    }
+
+    let result = match command {
+        Command::Serve => return serve(config, absolute_path, serde_json::to_value(&cli.overrides)?).await,
+        Command::CheckConfig => cli::check_config(&config, &sources),
+        Command::CanvasAt { at } => cli::canvas_at(&config, at).await,
+        Command::Export { output, at } => cli::export(&config, &output, at).await,
+        Command::Import { input } => cli::import(&config, &input).await,
+        Command::Snapshot => cli::snapshot(&config).await,
+        Command::Restore { at } => cli::restore(&config, at).await,
+        Command::Migrate { from } => match config_loader::load_file(&from) {
+            Ok(previous_config) => cli::migrate(&config, &previous_config).await,
+            Err(err) => Err(format!("Failed to load previous config: {err}").into()),
+        },
+        Command::Timelapse(args) => cli::timelapse(&config, &args.into()).await,
     };
+    result.unwrap_or_else(|err| exit_with_error(err));
+    Ok(())
+}
+
+fn exit_with_error<E: std::fmt::Display>(err: E) -> ! {
+    log::error!("{err}");
+    std::process::exit(1);
+}
+
+async fn serve(config: model::Config, config_path: PathBuf, flags: serde_json::Value) -> std::io::Result<()> {
+    let shared_config = model::SharedConfig::from(config.clone());
 
     // real-time db config
-    let redis_url = config.redis_url.clone();
-    log::info!("Starting redis on {}", &redis_url);
-    let redis_client = redis::Client::open(redis_url).unwrap();
+    let store = store::open_store(&shared_config).await.expect("Failed to open canvas storage");
 
     // place server
-    let server = actors::PlaceServer::new(redis_client.clone(), config.clone()).start();
+    let relay = actors::place_relay::open_relay(&config).await.expect("Failed to open pixel updates relay");
+    let server = actors::PlaceServer::new(store.clone(), shared_config.clone(), relay).start();
+    actors::ConfigWatcher::new(config_path, flags, shared_config.clone(), server.clone()).start();
+    let png_cache = web::Data::new(render::CanvasPngCache::new());
 
     // http server config
     let ip = config.host.clone();
-    let port = config.port.clone();
+    let port = config.port;
     let devmode = cfg!(debug_assertions);
     log::info!("starting HTTP server at http://{ip}:{port}");
     if devmode {  log::info!("Adding reverse proxy for frontend..."); }
@@ -67,8 +84,9 @@ async fn main() -> std::io::Result<()> {
             .wrap(cors)
             .app_data(web::Data::new(server.clone()))
             // .app_data(web::JsonConfig::default().limit(1024)) // <- limit size of the payload (global configuration)
-            .app_data(web::Data::new(redis_client.clone())) // db connection
-            .app_data(web::Data::new(config.clone())) // canvas config
+            .app_data(web::Data::from(store.clone())) // canvas store
+            .app_data(web::Data::new(shared_config.clone())) // canvas config, replaced on reload
+            .app_data(png_cache.clone()) // rendered canvas images
             // .wrap(actix_web::middleware::Logger::new("%a \"%r\" %s %b \"%{Referer}i\" %T")) // log things to stdout
             .configure(routes);
 
@@ -3,19 +3,18 @@ use actix_web::{HttpResponse, ResponseError, http::StatusCode};
 use redis::RedisError;
 use serde::{Deserialize, Deserializer, Serialize};
 use serde_json;
-use std::fs::File;
+use sha1::{Digest, Sha1};
 use std::error::Error;
-use std::io::BufReader;
 use std::io::Write;
-use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 
 const CANVAS_SIZE_DEFAULT: usize = 256;
 fn canvas_size_default() -> usize { CANVAS_SIZE_DEFAULT }
-const PIXELS_PER_BYTES_DEFAULT: usize = 2;
-fn pixels_per_bytes_default() -> usize { PIXELS_PER_BYTES_DEFAULT }
 
 #[cfg(debug_assertions)]
 const WEB_PORT: u16 = 8080;
@@ -23,12 +22,49 @@ const WEB_PORT: u16 = 8080;
 const WEB_PORT: u16 = 80;
 fn web_port_default() -> u16 { WEB_PORT }
 
+const SNAPSHOT_INTERVAL_DEFAULT: Duration = Duration::from_secs(3600);
+fn snapshot_interval_default() -> Duration { SNAPSHOT_INTERVAL_DEFAULT }
+
+const TICK_INTERVAL_DEFAULT: Duration = Duration::from_millis(50);
+fn tick_interval_default() -> Duration { TICK_INTERVAL_DEFAULT }
+
+const EMBEDDED_PATH_DEFAULT: &str = "place.redb";
+fn embedded_path_default() -> PathBuf { PathBuf::from(EMBEDDED_PATH_DEFAULT) }
+
 pub type ConfigColor = [u8; 3];
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum StorageKind {
+    #[default]
+    Redis,
+    Memory,
+    Embedded,
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "redis" => Ok(Self::Redis),
+            "memory" => Ok(Self::Memory),
+            "embedded" => Ok(Self::Embedded),
+            _ => Err(format!("Unknown storage {s}, expected redis, memory or embedded"))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Config {
+    #[serde(default)]
+    pub storage: StorageKind,
+    #[serde(default)]
     pub redis_url: String,
+    /// Embedded storage file, relative to the config file, or to the working directory when overridden
+    #[serde(default = "embedded_path_default")]
+    pub embedded_path: PathBuf,
     pub host: String,
     #[serde(default = "web_port_default")]
     pub port: u16,
@@ -43,11 +79,63 @@ pub struct Config {
     pub base_pixel_amount: usize,
     #[serde(deserialize_with = "deserialize_duration_seconds")]
     pub timeout: Duration,
-    #[serde(default = "pixels_per_bytes_default")]
-    pub pixels_per_bytes: usize,
+    #[serde(default)]
+    pub quota_mode: QuotaMode,
+    /// Interval of canvas snapshots used by time travel, 0 to disable
+    #[serde(default = "snapshot_interval_default", deserialize_with = "deserialize_duration_seconds")]
+    pub snapshot_interval: Duration,
+    /// Pixel updates are sent to sessions in one frame per tick, 0 to send them right away
+    #[serde(default = "tick_interval_default", deserialize_with = "deserialize_duration_millis")]
+    pub tick_interval: Duration,
 
     pub colors: Vec<ConfigColor>,
     pub colors_active: Option<Vec<usize>>,
+
+    #[serde(default)]
+    pub features: Features,
+
+    /// Bits per pixel of stored chunks when the palette does not tell them, only set by `migrate` to read them
+    #[serde(skip)]
+    pub stored_bits_per_pixel: Option<usize>,
+}
+
+/// Parts of the place that can be turned off, all on by default
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Features {
+    /// Pixels can be placed, the canvas is read only otherwise
+    pub placement: bool,
+    /// Pixel history endpoint
+    pub history: bool,
+    /// Canvas as it was at a past time, from the API
+    pub time_travel: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self { placement: true, history: true, time_travel: true }
+    }
+}
+
+/// Config read by the server, stores and controllers, replaced as a whole when reloaded
+#[derive(Debug, Clone)]
+pub struct SharedConfig(Arc<RwLock<Arc<Config>>>);
+
+impl SharedConfig {
+    /// Current config, kept as is by holders even if a reload happens meanwhile
+    pub fn get(&self) -> Arc<Config> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn set(&self, config: Config) {
+        *self.0.write().unwrap() = Arc::new(config);
+    }
+}
+
+impl From<Config> for SharedConfig {
+    fn from(config: Config) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(config))))
+    }
 }
 
 fn deserialize_duration_seconds<'de, D>(deserializer: D) -> Result<Duration, D::Error>
@@ -58,31 +146,201 @@ where
     Ok(Duration::from_secs(seconds))
 }
 
+fn deserialize_duration_millis<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let millis = u64::deserialize(deserializer)?;
+    Ok(Duration::from_millis(millis))
+}
+
 pub type ChunkLocation = ((usize, usize), (usize, usize));
 
-impl Config {
-    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
-        // Open file in RO mode with buffer
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
+/// Largest canvas side, positions are sent as 16 bits
+const CANVAS_SIZE_MAX: usize = u16::MAX as usize + 1;
+/// Most active colors, pixel colors are sent as 8 bits
+const ACTIVE_COLORS_MAX: usize = 256;
 
-        // Read the JSON contents of the file
-        let result: Config = serde_json::from_reader(reader)?;
+/// Invalid config value, with the path of the field in the config file
+#[derive(Debug, Clone)]
+pub struct ConfigError {
+    pub path: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new<P: Into<String>, M: Into<String>>(path: P, message: M) -> Self {
+        Self { path: path.into(), message: message.into() }
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Every problem found in a config, one per line
+#[derive(Debug, Clone)]
+pub struct ConfigErrors(pub Vec<ConfigError>);
 
-        Ok(result)
+impl std::fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (index, error) in self.0.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{error}")?;
+        }
+        Ok(())
     }
+}
+
+impl Error for ConfigErrors {}
+
+impl Config {
     fn chunk_number(&self, size: usize) -> usize {
-        (size / self.canvas_chunk_size + if size % self.canvas_chunk_size != 0 { 1 } else { 0 }).into()
+        size.div_ceil(self.canvas_chunk_size)
     }
     pub fn canvas_chunks(&self) -> (usize, usize) {
         (self.chunk_number(self.canvas_width), self.chunk_number(self.canvas_height))
     }
+    /// Colors in the order pixel colors index them
+    pub fn active_colors(&self) -> Vec<ConfigColor> {
+        if let Some(colors_active) = &self.colors_active {
+            let mut filtered_ordered_colors = Vec::with_capacity(colors_active.len());
+            for color_index in colors_active {
+                if let Some(color) = self.colors.get(*color_index) {
+                    filtered_ordered_colors.push(*color);
+                }
+            }
+            filtered_ordered_colors
+        } else {
+            self.colors.clone()
+        }
+    }
+    /// Indices in `colors` of the active colors, in the order pixel colors index them
+    fn active_color_indices(&self) -> Vec<usize> {
+        match &self.colors_active {
+            Some(colors_active) => colors_active.iter().copied().filter(|color_index| *color_index < self.colors.len()).collect(),
+            None => (0..self.colors.len()).collect(),
+        }
+    }
+    /// Number of active colors, without building the palette
+    pub fn active_color_count(&self) -> usize {
+        match &self.colors_active {
+            Some(colors_active) => colors_active.iter().filter(|color_index| **color_index < self.colors.len()).count(),
+            None => self.colors.len(),
+        }
+    }
+    /// Bits storing a pixel color, the smallest of 1, 2, 4 or 8 fitting every active color
+    ///
+    /// Stored chunks must be migrated when it changes
+    pub fn bits_per_pixel(&self) -> usize {
+        if let Some(bits_per_pixel) = self.stored_bits_per_pixel {
+            return bits_per_pixel;
+        }
+        let color_count = self.active_color_count();
+        [1, 2, 4].into_iter().find(|bits| color_count <= 1 << bits).unwrap_or(8)
+    }
     pub fn canvas_pos_to_chunk_location(&self, pos_x: usize, pos_y: usize) -> ChunkLocation {
         let chunk_index = (pos_x / self.canvas_chunk_size, pos_y / self.canvas_chunk_size);
         let chunk_pos = (pos_x % self.canvas_chunk_size, pos_y % self.canvas_chunk_size);
 
         (chunk_index, chunk_pos)
     }
+
+    /// Fields changed by new config which cannot be applied while running
+    ///
+    /// Only palette, pixel quota, timeout and features can be reloaded, as long as pixels keep the same bits
+    /// and active colors are only appended, so that stored pixels keep their color.
+    pub fn reload_errors(&self, new: &Config) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        let changed = [
+            ("storage", self.storage != new.storage),
+            ("redisUrl", self.redis_url != new.redis_url),
+            ("embeddedPath", self.embedded_path != new.embedded_path),
+            ("host", self.host != new.host),
+            ("port", self.port != new.port),
+            ("canvasWidth", self.canvas_width != new.canvas_width),
+            ("canvasHeight", self.canvas_height != new.canvas_height),
+            ("canvasChunkSize", self.canvas_chunk_size != new.canvas_chunk_size),
+            ("quotaMode", self.quota_mode != new.quota_mode),
+            ("snapshotInterval", self.snapshot_interval != new.snapshot_interval),
+            ("tickInterval", self.tick_interval != new.tick_interval),
+        ];
+        for (path, _) in changed.into_iter().filter(|(_, changed)| *changed) {
+            errors.push(ConfigError::new(path, "cannot change without restart"));
+        }
+
+        if self.bits_per_pixel() != new.bits_per_pixel() {
+            errors.push(ConfigError::new("colorsActive", format!(
+                "{} active colors need {} bits per pixel instead of {}, canvas must be migrated",
+                new.active_color_count(), new.bits_per_pixel(), self.bits_per_pixel(),
+            )));
+        } else if !new.active_color_indices().starts_with(&self.active_color_indices()) {
+            errors.push(ConfigError::new("colorsActive", "active colors can only be appended while running, stored pixels would change color"));
+        }
+
+        errors
+    }
+
+    /// Check values which would break at runtime, reports every problem found
+    pub fn validate(&self) -> Result<(), ConfigErrors> {
+        let mut errors = Vec::new();
+
+        if self.storage == StorageKind::Redis && self.redis_url.is_empty() {
+            errors.push(ConfigError::new("redisUrl", "required with redis storage"));
+        }
+
+        for (path, size) in [("canvasWidth", self.canvas_width), ("canvasHeight", self.canvas_height)] {
+            if size == 0 || size > CANVAS_SIZE_MAX {
+                errors.push(ConfigError::new(path, format!("{size} is not between 1 and {CANVAS_SIZE_MAX}, positions are sent as 16 bits")));
+            }
+        }
+        if self.canvas_chunk_size == 0 {
+            errors.push(ConfigError::new("canvasChunkSize", "must be at least 1"));
+        } else {
+            // chunks are whole bytes
+            let bits_per_pixel = self.bits_per_pixel();
+            let chunk_size_multiple = match bits_per_pixel { 1 => 4, 2 | 4 => 2, _ => 1 };
+            if !self.canvas_chunk_size.is_multiple_of(chunk_size_multiple) {
+                errors.push(ConfigError::new("canvasChunkSize", format!(
+                    "must be a multiple of {chunk_size_multiple} to pack pixels of {bits_per_pixel} bits in whole bytes"
+                )));
+            }
+        }
+
+        if self.base_pixel_amount == 0 {
+            errors.push(ConfigError::new("basePixelAmount", "must be at least 1"));
+        }
+        if self.timeout.is_zero() {
+            errors.push(ConfigError::new("timeout", "must be at least 1 second"));
+        }
+
+        if self.colors.is_empty() {
+            errors.push(ConfigError::new("colors", "at least one color is required"));
+        }
+        if let Some(colors_active) = &self.colors_active {
+            if colors_active.is_empty() {
+                errors.push(ConfigError::new("colorsActive", "at least one color is required"));
+            }
+            for (index, color_index) in colors_active.iter().enumerate() {
+                if *color_index >= self.colors.len() {
+                    errors.push(ConfigError::new(format!("colorsActive[{index}]"), format!("color {color_index} does not exist, colors has {} entries", self.colors.len())));
+                } else if colors_active[..index].contains(color_index) {
+                    errors.push(ConfigError::new(format!("colorsActive[{index}]"), format!("color {color_index} is already active")));
+                }
+            }
+        }
+        let active_color_count = self.active_color_count();
+        if active_color_count > ACTIVE_COLORS_MAX {
+            let path = if self.colors_active.is_some() { "colorsActive" } else { "colors" };
+            errors.push(ConfigError::new(path, format!("{active_color_count} active colors, at most {ACTIVE_COLORS_MAX} are supported")));
+        }
+
+        if errors.is_empty() { Ok(()) } else { Err(ConfigErrors(errors)) }
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -106,6 +364,8 @@ impl std::fmt::Display for BackendError {
     }
 }
 
+impl Error for BackendError {}
+
 impl ResponseError for BackendError {
     fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
         HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
@@ -126,6 +386,15 @@ impl From<RedisError> for BackendError {
         }
     }
 }
+impl From<redb::Error> for BackendError {
+    fn from(val: redb::Error) -> Self {
+        log::error!("redb::Error: {:?}", val);
+        BackendError {
+            error: "Error with embedded storage",
+            details: val.to_string()
+        }
+    }
+}
 impl From<serde_json::Error> for BackendError {
     fn from(val: serde_json::Error) -> Self {
         log::error!("serde_json::Error: {:?}", val);
@@ -136,17 +405,35 @@ impl From<serde_json::Error> for BackendError {
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Canvas {
-    pub valid: bool,
-    pub colors: Vec<u8>
+/// How spent client pixels come back
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum QuotaMode {
+    /// All pixels come back at once, `timeout` after the previous refill and once all are spent
+    #[default]
+    RefillAll,
+    /// One pixel comes back every `timeout / basePixelAmount`, up to `basePixelAmount`
+    TokenBucket,
+}
+
+impl QuotaMode {
+    /// Name passed to `place_pixel.lua`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::RefillAll => "refillAll",
+            Self::TokenBucket => "tokenBucket",
+        }
+    }
 }
 
-impl Canvas {
-    pub fn new(canvas_width: usize, canvas_height: usize) -> Self {
-        Canvas {
-            colors: vec![0; canvas_width as usize * canvas_height as usize / 2],
-            valid: false
+impl FromStr for QuotaMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "refillAll" => Ok(Self::RefillAll),
+            "tokenBucket" => Ok(Self::TokenBucket),
+            _ => Err(format!("Unknown quota mode {s}, expected refillAll or tokenBucket"))
         }
     }
 }
@@ -159,7 +446,7 @@ pub struct Profile {
     pub image: String
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Client {
     pub last_timestamp: u64,
@@ -177,9 +464,90 @@ impl Client {
     pub fn timestamp_now() -> u64 {
         let start = SystemTime::now();
         let since_the_epoch = start.duration_since(UNIX_EPOCH).expect("Time went backwards");
-        let timestamp = since_the_epoch.as_secs();
+        since_the_epoch.as_secs()
+    }
+
+    /// Give back the pixels due according to `Config::quota_mode`, `true` if some were given back
+    ///
+    /// Every quota check goes through here, mirrored by `place_pixel.lua` for redis
+    pub fn refill(&mut self, config: &Config, now: u64) -> bool {
+        match config.quota_mode {
+            QuotaMode::RefillAll => {
+                // agree with 1s margin
+                if self.remaining_pixels == 0 && now + 1 >= self.next_timestamp(config) {
+                    self.remaining_pixels = config.base_pixel_amount;
+                    self.last_timestamp = now;
+                    return true;
+                }
+                false
+            },
+            QuotaMode::TokenBucket => {
+                let timeout_secs = config.timeout.as_secs();
+                let base_pixel_amount = config.base_pixel_amount as u64;
+
+                // full bucket does not regenerate, clock starts with the next spent pixel
+                if self.remaining_pixels >= config.base_pixel_amount {
+                    self.last_timestamp = now;
+                    return false;
+                }
+                if timeout_secs == 0 {
+                    self.remaining_pixels = config.base_pixel_amount;
+                    self.last_timestamp = now;
+                    return true;
+                }
+
+                let regenerated = now.saturating_sub(self.last_timestamp) * base_pixel_amount / timeout_secs;
+                if regenerated == 0 {
+                    return false;
+                }
+
+                self.remaining_pixels += regenerated as usize;
+                if self.remaining_pixels >= config.base_pixel_amount {
+                    self.remaining_pixels = config.base_pixel_amount;
+                    self.last_timestamp = now;
+                } else {
+                    // keep time elapsed toward the next pixel, rounded in favor of the server
+                    self.last_timestamp += (regenerated * timeout_secs).div_ceil(base_pixel_amount);
+                }
+                true
+            },
+        }
+    }
+
+    /// Refill pixels if possible then spend one, `false` if none left
+    pub fn take_pixel(&mut self, config: &Config, now: u64) -> bool {
+        self.refill(config, now);
 
-        timestamp
+        if self.remaining_pixels == 0 {
+            return false;
+        }
+        self.remaining_pixels -= 1;
+        true
+    }
+
+    /// Timestamp from which spent pixels are refilled, or the next pixel with `QuotaMode::TokenBucket`
+    pub fn next_timestamp(&self, config: &Config) -> u64 {
+        match config.quota_mode {
+            QuotaMode::RefillAll => self.last_timestamp + config.timeout.as_secs(),
+            QuotaMode::TokenBucket => self.last_timestamp + config.timeout.as_secs().div_ceil(config.base_pixel_amount.max(1) as u64),
+        }
+    }
+
+    /// Pixels come back at `next_timestamp` without spending any more
+    pub fn refilling(&self, config: &Config) -> bool {
+        match config.quota_mode {
+            QuotaMode::RefillAll => self.remaining_pixels == 0,
+            QuotaMode::TokenBucket => self.remaining_pixels < config.base_pixel_amount,
+        }
+    }
+
+    pub fn cooldown(&self, config: &Config) -> Cooldown {
+        Cooldown {
+            remaining_pixels: self.remaining_pixels,
+            last_timestamp: self.last_timestamp,
+            next_timestamp: self.next_timestamp(config),
+            refilling: self.refilling(config),
+        }
     }
     pub fn encode_json(&self) -> Result<std::string::String, serde_json::Error> {
         serde_json::to_string(self)
@@ -189,17 +557,14 @@ impl Client {
     }
 }
 
-impl Client {
-    pub fn from_redis<S: AsRef<str>>(result: Result<S, RedisError>, base_pixel_amount: usize) -> Self {
-        result.ok()
-            .and_then(|client_string| Self::decode_json(client_string).ok())
-            .unwrap_or(Self::new(base_pixel_amount))
-    }
-    pub fn try_from_redis<S: AsRef<str>>(result: Result<S, RedisError>) -> Result<Self, String> {
-        result
-            .map_err(|e| e.to_string())
-            .and_then(|client_string| Self::decode_json(client_string).map_err(|e| e.to_string()))
-    }
+/// Client quota state pushed to its session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cooldown {
+    pub remaining_pixels: usize,
+    pub last_timestamp: u64,
+    pub next_timestamp: u64,
+    /// Pushed again at `next_timestamp`, not sent to the client
+    pub refilling: bool,
 }
 
 pub type PixelColorUpdateMessageColor = u8;
@@ -213,8 +578,10 @@ pub struct PixelColorUpdateMessage {
 }
 
 impl PixelColorUpdateMessage {
+    pub const SERIALIZED_SIZE: usize = 5;
+
     pub fn deserialize(data: &[u8]) -> Result<Self, &'static str> {
-        if data.len() < 5 {
+        if data.len() < Self::SERIALIZED_SIZE {
             return Err("Error deserializing pixel color update");
         }
 
@@ -237,22 +604,208 @@ impl PixelColorUpdateMessage {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Message)]
-#[rtype(result = "Result<(), String>")]
-pub struct UserPixelColorMessage {
+/// Accepted pixel update with its global placement sequence number
+#[derive(Debug, Clone)]
+pub struct SequencedPixelUpdate {
+    pub seq: u64,
     pub pixel_update: PixelColorUpdateMessage,
-    pub uuid: String
 }
 
-impl UserPixelColorMessage {
-    pub fn new(uuid: String, bin: &[u8]) -> Result<UserPixelColorMessage, String> {
-        Ok(UserPixelColorMessage {
-            pixel_update: PixelColorUpdateMessage::deserialize(bin).map_err(|e| e.to_string())?,
-            uuid,
+impl SequencedPixelUpdate {
+    /// Pixel update bytes followed by sequence number, thus readable as a plain pixel update
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buffer = self.pixel_update.serialize();
+        buffer.write_all(&self.seq.to_be_bytes()).unwrap();
+        buffer
+    }
+
+    /// Serialized updates one after another, in a single frame
+    pub fn serialize_batch(updates: &[Self]) -> Vec<u8> {
+        updates.iter().flat_map(Self::serialize).collect()
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Self, &'static str> {
+        let pixel_update = PixelColorUpdateMessage::deserialize(data)?;
+        let seq_bytes = data.get(5..13).ok_or("Error deserializing pixel update sequence number")?;
+
+        Ok(Self {
+            seq: u64::from_be_bytes(seq_bytes.try_into().unwrap()),
+            pixel_update,
         })
     }
 }
 
+/// Accepted placement kept in pixel history
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PixelHistoryEntry {
+    pub pos_x: u16,
+    pub pos_y: u16,
+    pub color: PixelColorUpdateMessageColor,
+    /// Session uuid of the author
+    pub uuid: String,
+    pub timestamp: u64,
+}
+
+impl PixelHistoryEntry {
+    pub fn new(uuid: &str, pixel_update: &PixelColorUpdateMessage, timestamp: u64) -> Self {
+        Self {
+            pos_x: pixel_update.pos_x,
+            pos_y: pixel_update.pos_y,
+            color: pixel_update.color,
+            uuid: uuid.to_string(),
+            timestamp,
+        }
+    }
+    /// Public author id, hash of the session uuid
+    pub fn author(&self) -> String {
+        let digest = Sha1::digest(self.uuid.as_bytes());
+        digest[..8].iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+    pub fn encode_json(&self) -> Result<std::string::String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+    pub fn decode_json<S: AsRef<str>>(str: S) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(str.as_ref())
+    }
+}
+
+/// Reason of a rejected placement
+#[derive(Debug, Clone)]
+pub enum PlacementError {
+    InvalidPosition,
+    InvalidColor,
+    NoPixelsLeft,
+    /// Placement is turned off in features
+    Disabled,
+    /// Store failed, details are only logged
+    Storage,
+}
+
+impl PlacementError {
+    /// Only a broken or malicious client sends such placement
+    pub fn is_violation(&self) -> bool {
+        matches!(self, Self::InvalidPosition | Self::InvalidColor)
+    }
+}
+
+impl std::fmt::Display for PlacementError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidPosition => write!(f, "Invalid position in canvas"),
+            Self::InvalidColor => write!(f, "Invalid color in palette"),
+            Self::NoPixelsLeft => write!(f, "No pixels left"),
+            Self::Disabled => write!(f, "Placing pixels is disabled"),
+            Self::Storage => write!(f, "Failed to save pixel"),
+        }
+    }
+}
+
+/// Place a client pixel, result is the placement sequence number
+#[derive(Debug, Serialize, Deserialize, Clone, Message)]
+#[rtype(result = "Result<u64, PlacementError>")]
+pub struct UserPixelColorMessage {
+    pub pixel_update: PixelColorUpdateMessage,
+    pub uuid: String
+}
+
 pub const SESSION_COOKIE_NAME: &str = "sessionUUID";
 
 pub const CANVAS_DB_KEY: &str = "canvas";
+
+pub const HISTORY_DB_KEY: &str = "history";
+
+pub const SNAPSHOT_DB_KEY: &str = "snapshot";
+
+pub const HISTORY_SEQ_DB_KEY: &str = "history_seq";
+
+pub const LAYOUT_DB_KEY: &str = "canvas_layout";
+
+pub const MIGRATION_DB_KEY: &str = "canvas_migration";
+
+pub const EPOCH_DB_KEY: &str = "canvas_epoch";
+
+#[cfg(test)]
+impl Config {
+    /// Config shared by tests, `color_count` colors on a 8x8 canvas with chunks of 3 pixels and 3 pixels every 30 seconds
+    ///
+    /// Chunk rows never end on a byte boundary and edge chunks go past the canvas.
+    pub fn test(color_count: usize) -> Self {
+        serde_json::from_value(serde_json::json!({
+            "storage": "memory",
+            "host": "localhost",
+            "canvasWidth": 8,
+            "canvasHeight": 8,
+            "canvasChunkSize": 3,
+            "basePixelAmount": 3,
+            "timeout": 30,
+            "colors": (0..color_count).map(|index| [index as u8, 0, 0]).collect::<Vec<_>>(),
+        })).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reload_only_appends_active_colors() {
+        let config = Config { colors_active: Some(vec![0, 1, 2]), ..Config::test(4) };
+        let with_active = |colors_active: Vec<usize>| Config { colors_active: Some(colors_active), ..Config::test(4) };
+        let error_paths = |new: &Config| config.reload_errors(new).into_iter().map(|error| error.path).collect::<Vec<_>>();
+
+        assert!(error_paths(&with_active(vec![0, 1, 2])).is_empty());
+        assert!(error_paths(&with_active(vec![0, 1, 2, 3])).is_empty());
+        // same bits per pixel, but stored pixels would change color
+        assert_eq!(error_paths(&with_active(vec![1, 0, 2])), vec!["colorsActive"]);
+        assert_eq!(error_paths(&with_active(vec![0, 1, 3])), vec!["colorsActive"]);
+        assert_eq!(error_paths(&with_active(vec![0, 1, 3, 2])), vec!["colorsActive"]);
+    }
+
+    #[test]
+    fn refill_all_once_every_pixel_spent() {
+        let config = Config { quota_mode: QuotaMode::RefillAll, ..Config::test(2) };
+        let mut client = Client { last_timestamp: 1000, remaining_pixels: 3 };
+
+        for _ in 0..3 {
+            assert!(client.take_pixel(&config, 1000));
+        }
+        assert!(!client.take_pixel(&config, 1000));
+        assert!(client.refilling(&config));
+
+        assert!(!client.refill(&config, 1028));
+        assert_eq!(client.remaining_pixels, 0);
+        // 1s margin
+        assert!(client.refill(&config, 1029));
+        assert_eq!((client.remaining_pixels, client.last_timestamp), (3, 1029));
+
+        // partially spent pixels are not refilled
+        assert!(client.take_pixel(&config, 1030));
+        assert!(!client.refill(&config, 2000));
+        assert_eq!(client.remaining_pixels, 2);
+    }
+
+    #[test]
+    fn token_bucket_one_pixel_at_a_time() {
+        let config = Config { quota_mode: QuotaMode::TokenBucket, ..Config::test(2) };
+        let mut client = Client { last_timestamp: 0, remaining_pixels: 3 };
+
+        // full bucket restarts the clock on the next spent pixel
+        assert!(!client.refill(&config, 1000));
+        assert_eq!(client.last_timestamp, 1000);
+        assert!(client.take_pixel(&config, 1000));
+        assert!(client.take_pixel(&config, 1000));
+        assert_eq!(client.next_timestamp(&config), 1010);
+
+        assert!(!client.refill(&config, 1009));
+        assert_eq!(client.remaining_pixels, 1);
+        // time toward the next pixel is kept
+        assert!(client.refill(&config, 1015));
+        assert_eq!((client.remaining_pixels, client.last_timestamp), (2, 1010));
+
+        // never more than the base amount
+        assert!(client.refill(&config, 2000));
+        assert_eq!((client.remaining_pixels, client.last_timestamp), (3, 2000));
+        assert!(!client.refilling(&config));
+    }
+}
@@ -0,0 +1,253 @@
+use std::sync::Arc;
+
+use crate::model::{Cooldown, PixelColorUpdateMessage, PlacementError, SequencedPixelUpdate};
+
+/// Version exchanged in the handshake, bumped on every incompatible change
+///
+/// New server message tags are not incompatible, clients ignore unknown tags
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// Close code when the first client message is not `Hello`, thus clients older than this protocol
+pub const CLOSE_HANDSHAKE_REQUIRED: u16 = 4000;
+/// Close code when the client protocol version is not supported
+pub const CLOSE_UNSUPPORTED_VERSION: u16 = 4001;
+
+/// Largest frame accepted from a client, enough to subscribe to 16384 chunks at once
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+// tags shared by both directions
+const TAG_HELLO: u8 = 0x01;
+const TAG_HEARTBEAT: u8 = 0x02;
+
+// client to server tags
+const TAG_PLACE: u8 = 0x10;
+const TAG_RESUME: u8 = 0x20;
+const TAG_SUBSCRIBE: u8 = 0x21;
+const TAG_UNSUBSCRIBE: u8 = 0x22;
+
+// server to client tags
+const TAG_PIXEL_UPDATES: u8 = 0x10;
+const TAG_RELOAD: u8 = 0x11;
+const TAG_ACK: u8 = 0x12;
+const TAG_CONFIG_CHANGED: u8 = 0x13;
+const TAG_SYNCED: u8 = 0x14;
+const TAG_ONLINE_COUNT: u8 = 0x30;
+const TAG_COOLDOWN: u8 = 0x31;
+const TAG_ERROR: u8 = 0x40;
+
+/// Kind of error sent back to the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ErrorCode {
+    /// Frame could not be decoded or is not expected
+    InvalidMessage = 1,
+    InvalidPosition = 2,
+    NoPixelsLeft = 3,
+    /// Placement could not be saved, may be retried
+    ServerError = 4,
+    /// Color is not an index of the active palette
+    InvalidColor = 5,
+    /// Placement is turned off
+    PlacementDisabled = 6,
+}
+
+impl From<&PlacementError> for ErrorCode {
+    fn from(err: &PlacementError) -> Self {
+        match err {
+            PlacementError::InvalidPosition => Self::InvalidPosition,
+            PlacementError::InvalidColor => Self::InvalidColor,
+            PlacementError::NoPixelsLeft => Self::NoPixelsLeft,
+            PlacementError::Disabled => Self::PlacementDisabled,
+            PlacementError::Storage => Self::ServerError,
+        }
+    }
+}
+
+/// Message sent by a client, first byte is the message tag
+#[derive(Debug, Clone)]
+pub enum ClientMessage {
+    /// `[version u8]`, must be the first message
+    Hello { version: u8 },
+    /// No payload, answered with a heartbeat
+    Heartbeat,
+    /// `[request_id u32][x u16][y u16][color u8]`, answered with an ack or an error with the same request id
+    Place { request_id: u32, pixel_update: PixelColorUpdateMessage },
+    /// `[seq u64]`, ask for updates missed after seq
+    Resume { from_seq: u64 },
+    /// `[cx u16][cy u16]` repeated, add chunks to the view
+    Subscribe(Vec<(usize, usize)>),
+    /// `[cx u16][cy u16]` repeated, remove chunks from the view
+    Unsubscribe(Vec<(usize, usize)>),
+}
+
+impl ClientMessage {
+    pub fn decode(data: &[u8]) -> Result<Self, &'static str> {
+        let (&tag, payload) = data.split_first().ok_or("Empty message")?;
+
+        match tag {
+            TAG_HELLO => match payload {
+                [version] => Ok(Self::Hello { version: *version }),
+                _ => Err("Invalid hello message"),
+            },
+            TAG_HEARTBEAT => match payload {
+                [] => Ok(Self::Heartbeat),
+                _ => Err("Invalid heartbeat message"),
+            },
+            TAG_PLACE => {
+                let (request_id, pixel_payload) = payload.split_first_chunk().ok_or("Invalid place message")?;
+                if pixel_payload.len() != PixelColorUpdateMessage::SERIALIZED_SIZE {
+                    return Err("Invalid place message");
+                }
+                Ok(Self::Place {
+                    request_id: u32::from_be_bytes(*request_id),
+                    pixel_update: PixelColorUpdateMessage::deserialize(pixel_payload)?,
+                })
+            },
+            TAG_RESUME => {
+                let seq_bytes = payload.try_into().map_err(|_| "Invalid resume message")?;
+                Ok(Self::Resume { from_seq: u64::from_be_bytes(seq_bytes) })
+            },
+            TAG_SUBSCRIBE => Ok(Self::Subscribe(decode_chunk_indices(payload)?)),
+            TAG_UNSUBSCRIBE => Ok(Self::Unsubscribe(decode_chunk_indices(payload)?)),
+            _ => Err("Unknown message tag"),
+        }
+    }
+}
+
+fn decode_chunk_indices(payload: &[u8]) -> Result<Vec<(usize, usize)>, &'static str> {
+    if payload.is_empty() || !payload.len().is_multiple_of(4) {
+        return Err("Invalid chunk indices");
+    }
+
+    Ok(payload.chunks_exact(4)
+        .map(|pair| (
+            u16::from_be_bytes([pair[0], pair[1]]).into(),
+            u16::from_be_bytes([pair[2], pair[3]]).into(),
+        ))
+        .collect())
+}
+
+/// Message sent to a client, first byte is the message tag
+#[derive(Debug, Clone)]
+pub enum ServerMessage {
+    /// `[version u8]`, answer to `Hello`
+    Welcome { version: u8 },
+    /// No payload, answer to a client heartbeat
+    Heartbeat,
+    /// `[x u16][y u16][color u8][seq u64]` repeated
+    PixelUpdates(Arc<[SequencedPixelUpdate]>),
+    /// No payload, missed updates are not buffered anymore and the whole canvas must be loaded again
+    Reload,
+    /// `[request_id u32][seq u64]`, placement accepted
+    Ack { request_id: u32, seq: u64 },
+    /// No payload, palette, quota or features changed and canvas info must be loaded again
+    ConfigChanged,
+    /// `[seq u64]`, every update up to seq was sent, those out of the session view left out, to resume from
+    Synced(u64),
+    /// `[count u32]`, sessions online on every instance
+    OnlineCount(usize),
+    /// `[remaining_pixels u32][last_timestamp u64][next_timestamp u64]`, after every placement and refill
+    Cooldown(Cooldown),
+    /// `[code u8][request_id u32][message utf8]`, request id is 0 when the error is not about a placement
+    Error { code: ErrorCode, request_id: u32, message: String },
+}
+
+impl ServerMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Self::Welcome { version } => vec![TAG_HELLO, *version],
+            Self::Heartbeat => vec![TAG_HEARTBEAT],
+            Self::PixelUpdates(updates) => {
+                let mut buffer = vec![TAG_PIXEL_UPDATES];
+                buffer.extend(SequencedPixelUpdate::serialize_batch(updates));
+                buffer
+            },
+            Self::Reload => vec![TAG_RELOAD],
+            Self::Ack { request_id, seq } => {
+                let mut buffer = vec![TAG_ACK];
+                buffer.extend(request_id.to_be_bytes());
+                buffer.extend(seq.to_be_bytes());
+                buffer
+            },
+            Self::ConfigChanged => vec![TAG_CONFIG_CHANGED],
+            Self::Synced(seq) => {
+                let mut buffer = vec![TAG_SYNCED];
+                buffer.extend(seq.to_be_bytes());
+                buffer
+            },
+            Self::OnlineCount(count) => {
+                let mut buffer = vec![TAG_ONLINE_COUNT];
+                buffer.extend(u32::try_from(*count).unwrap_or(u32::MAX).to_be_bytes());
+                buffer
+            },
+            Self::Cooldown(cooldown) => {
+                let mut buffer = vec![TAG_COOLDOWN];
+                buffer.extend(u32::try_from(cooldown.remaining_pixels).unwrap_or(u32::MAX).to_be_bytes());
+                buffer.extend(cooldown.last_timestamp.to_be_bytes());
+                buffer.extend(cooldown.next_timestamp.to_be_bytes());
+                buffer
+            },
+            Self::Error { code, request_id, message } => {
+                let mut buffer = vec![TAG_ERROR, *code as u8];
+                buffer.extend(request_id.to_be_bytes());
+                buffer.extend(message.as_bytes());
+                buffer
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_valid_messages() {
+        assert!(matches!(ClientMessage::decode(&[TAG_HELLO, PROTOCOL_VERSION]), Ok(ClientMessage::Hello { version: PROTOCOL_VERSION })));
+        assert!(matches!(ClientMessage::decode(&[TAG_HEARTBEAT]), Ok(ClientMessage::Heartbeat)));
+
+        let Ok(ClientMessage::Place { request_id, pixel_update }) = ClientMessage::decode(&[TAG_PLACE, 0, 0, 1, 2, 0, 3, 0, 4, 5]) else {
+            panic!("place message not decoded");
+        };
+        assert_eq!((request_id, pixel_update.pos_x, pixel_update.pos_y, pixel_update.color), (258, 3, 4, 5));
+
+        assert!(matches!(ClientMessage::decode(&[TAG_RESUME, 0, 0, 0, 0, 0, 0, 1, 0]), Ok(ClientMessage::Resume { from_seq: 256 })));
+        let Ok(ClientMessage::Subscribe(chunks)) = ClientMessage::decode(&[TAG_SUBSCRIBE, 0, 1, 0, 2, 1, 0, 0, 3]) else {
+            panic!("subscribe message not decoded");
+        };
+        assert_eq!(chunks, vec![(1, 2), (256, 3)]);
+    }
+
+    #[test]
+    fn decode_truncated_messages() {
+        let truncated: &[&[u8]] = &[
+            &[],
+            &[TAG_HELLO],
+            &[TAG_PLACE],
+            &[TAG_PLACE, 0, 0, 0, 1],
+            &[TAG_PLACE, 0, 0, 0, 1, 0, 3, 0, 4],
+            &[TAG_RESUME, 0, 0, 0, 0, 0, 0, 1],
+            &[TAG_SUBSCRIBE],
+            &[TAG_SUBSCRIBE, 0, 1, 0, 2, 0, 1],
+            &[TAG_UNSUBSCRIBE, 0, 1, 0],
+        ];
+        for data in truncated {
+            assert!(ClientMessage::decode(data).is_err(), "{data:?} decoded");
+        }
+    }
+
+    #[test]
+    fn decode_oversized_messages() {
+        let oversized: &[&[u8]] = &[
+            &[TAG_HELLO, PROTOCOL_VERSION, 0],
+            &[TAG_HEARTBEAT, 0],
+            &[TAG_PLACE, 0, 0, 0, 1, 0, 3, 0, 4, 5, 0],
+            &[TAG_RESUME, 0, 0, 0, 0, 0, 0, 0, 1, 0],
+            &[TAG_SUBSCRIBE, 0, 1, 0, 2, 0],
+        ];
+        for data in oversized {
+            assert!(ClientMessage::decode(data).is_err(), "{data:?} decoded");
+        }
+        assert!(ClientMessage::decode(&[0xff]).is_err());
+    }
+}
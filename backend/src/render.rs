@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use bytes::Bytes;
+
+use crate::model;
+use crate::store::{CanvasChunk, CanvasChunks};
+
+/// Rectangle of canvas pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Region {
+    pub fn full(config: &model::Config) -> Self {
+        Self { x: 0, y: 0, width: config.canvas_width, height: config.canvas_height }
+    }
+
+    /// Region restricted to the canvas, `None` if nothing is left
+    pub fn clamp(self, config: &model::Config) -> Option<Self> {
+        let x = self.x.min(config.canvas_width);
+        let y = self.y.min(config.canvas_height);
+        let width = self.width.min(config.canvas_width - x);
+        let height = self.height.min(config.canvas_height - y);
+
+        if width == 0 || height == 0 {
+            return None;
+        }
+        Some(Self { x, y, width, height })
+    }
+
+    /// Indices of the chunks holding pixels of the region
+    pub fn chunk_indices(self, config: &model::Config) -> impl Iterator<Item = (usize, usize)> {
+        let chunk_size = config.canvas_chunk_size;
+        let chunk_indices_x = self.x / chunk_size..(self.x + self.width).div_ceil(chunk_size);
+        let chunk_indices_y = self.y / chunk_size..(self.y + self.height).div_ceil(chunk_size);
+        chunk_indices_x.flat_map(move |chunk_index_x| chunk_indices_y.clone().map(move |chunk_index_y| (chunk_index_x, chunk_index_y)))
+    }
+}
+
+impl FromStr for Region {
+    type Err = String;
+
+    /// Parse `x,y,w,h`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s.split(',')
+            .map(|value| value.trim().parse::<usize>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Invalid region {s}: {e}"))?;
+
+        match values[..] {
+            [x, y, width, height] => Ok(Self { x, y, width, height }),
+            _ => Err(format!("Invalid region {s}: expected x,y,w,h"))
+        }
+    }
+}
+
+/// Color index of every canvas pixel, row by row
+pub fn canvas_color_indices(config: &model::Config, chunks: &CanvasChunks) -> Vec<u8> {
+    let mut indices = vec![0; config.canvas_width * config.canvas_height];
+
+    let canvas = Region::full(config);
+    for (chunk_index_x, chunk_row) in chunks.iter().enumerate() {
+        for (chunk_index_y, chunk) in chunk_row.iter().enumerate() {
+            chunk_color_indices_write(config, &mut indices, canvas, (chunk_index_x, chunk_index_y), chunk);
+        }
+    }
+
+    indices
+}
+
+/// Write the color indices of one chunk into the indices of `area`, row by row
+fn chunk_color_indices_write(config: &model::Config, indices: &mut [u8], area: Region, chunk_index: (usize, usize), chunk: &[u8]) {
+    let (chunk_index_x, chunk_index_y) = chunk_index;
+    let chunk_size = config.canvas_chunk_size;
+
+    // chunks on the area edge may go past it
+    for (chunk_pos_index, color) in CanvasChunk::chunk_colors(config, chunk).into_iter().enumerate() {
+        let pos_x = chunk_index_x * chunk_size + chunk_pos_index % chunk_size;
+        let pos_y = chunk_index_y * chunk_size + chunk_pos_index / chunk_size;
+        if (area.x..area.x + area.width).contains(&pos_x) && (area.y..area.y + area.height).contains(&pos_y) {
+            indices[(pos_y - area.y) * area.width + pos_x - area.x] = color;
+        }
+    }
+}
+
+/// Color indices of a region of rows `width` pixels long, each pixel repeated `scale` times in both directions
+pub fn region_scaled(indices: &[u8], width: usize, region: Region, scale: usize) -> Vec<u8> {
+    let mut result = Vec::with_capacity(region.width * scale * region.height * scale);
+
+    for pos_y in region.y..region.y + region.height {
+        let row_start = result.len();
+        for pos_x in region.x..region.x + region.width {
+            let color = indices[pos_y * width + pos_x];
+            result.extend(std::iter::repeat_n(color, scale));
+        }
+        for _ in 1..scale {
+            result.extend_from_within(row_start..row_start + region.width * scale);
+        }
+    }
+
+    result
+}
+
+/// RGB palette indexed by stored pixel colors, padded to every value a pixel can hold
+pub fn palette_rgb(config: &model::Config) -> Vec<u8> {
+    let mut palette: Vec<u8> = config.active_colors().into_iter().flatten().collect();
+    let color_count = 1 << config.bits_per_pixel();
+    palette.resize(palette.len().max(color_count * 3), 0);
+    palette
+}
+
+/// Write an 8 bits indexed PNG image
+pub fn write_png<W: Write>(w: W, width: usize, height: usize, palette: &[u8], data: &[u8]) -> Result<(), png::EncodingError> {
+    let mut encoder = png::Encoder::new(w, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(palette);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(data)?;
+    writer.finish()
+}
+
+/// Maximum number of rendered images kept by `CanvasPngCache`
+const PNG_CACHE_IMAGES_MAX: usize = 32;
+/// Largest canvas whose color indices are kept by `CanvasPngCache`, one byte per pixel
+///
+/// Chunks of bigger canvases are read and decoded again for every image, only those in its region.
+const PNG_CACHE_CANVAS_PIXELS_MAX: usize = 4096 * 4096;
+
+/// Whether the color indices of the whole canvas fit in `CanvasPngCache`
+fn png_cache_fits(config: &model::Config) -> bool {
+    config.canvas_width * config.canvas_height <= PNG_CACHE_CANVAS_PIXELS_MAX
+}
+
+#[derive(Default)]
+struct CanvasPngCacheState {
+    /// Store epoch the chunk versions belong to
+    epoch: String,
+    /// Canvas width and height the color indices were decoded for
+    canvas_size: (usize, usize),
+    /// Version of every decoded chunk, indexed by `[chunk_index_x][chunk_index_y]`, `None` if not decoded yet
+    chunk_versions: Vec<Vec<Option<u64>>>,
+    /// Color index of every canvas pixel, row by row
+    indices: Vec<u8>,
+    /// Bumped every time a chunk or the palette changed
+    generation: u64,
+    /// Palette images were rendered with, the config may be reloaded with another one
+    palette: Vec<u8>,
+    /// Encoded images with the generation they were rendered at
+    images: HashMap<(Region, usize), (u64, Bytes)>,
+}
+
+impl CanvasPngCacheState {
+    /// Whether decoded chunks are still valid for this store epoch and canvas size
+    fn matches(&self, config: &model::Config, epoch: &str) -> bool {
+        self.epoch == epoch && self.canvas_size == (config.canvas_width, config.canvas_height)
+    }
+}
+
+/// Chunk bytes read from the store with the version they were read at
+pub struct ChunkUpdate {
+    pub chunk_index: (usize, usize),
+    pub version: u64,
+    pub chunk: Vec<u8>,
+}
+
+/// Rendered canvas PNG images, only chunks whose version changed are decoded again
+///
+/// Canvases above `PNG_CACHE_CANVAS_PIXELS_MAX` are not cached.
+#[derive(Default)]
+pub struct CanvasPngCache {
+    state: Mutex<CanvasPngCacheState>,
+}
+
+impl CanvasPngCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Chunks to read again to render `region`, their decoded colors are missing or older than `chunk_versions`
+    pub fn chunks_stale(&self, config: &model::Config, epoch: &str, chunk_versions: &[Vec<u64>], region: Region) -> Vec<(usize, usize)> {
+        if !png_cache_fits(config) {
+            return region.chunk_indices(config).collect();
+        }
+
+        let state = self.state.lock().unwrap();
+        let matches = state.matches(config, epoch);
+
+        let mut stale = Vec::new();
+        for (chunk_index_x, versions) in chunk_versions.iter().enumerate() {
+            for (chunk_index_y, version) in versions.iter().enumerate() {
+                let decoded_version = state.chunk_versions.get(chunk_index_x)
+                    .and_then(|decoded_versions| decoded_versions.get(chunk_index_y))
+                    .copied()
+                    .flatten();
+                if !matches || decoded_version != Some(*version) {
+                    stale.push((chunk_index_x, chunk_index_y));
+                }
+            }
+        }
+
+        stale
+    }
+
+    /// Get the PNG image of a canvas region once updated chunks are decoded, rendering it only if the canvas changed since last time
+    pub fn canvas_png(&self, config: &model::Config, epoch: &str, chunk_updates: Vec<ChunkUpdate>, region: Region, scale: usize) -> Result<Bytes, png::EncodingError> {
+        if !png_cache_fits(config) {
+            return region_png(config, chunk_updates, region, scale);
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        if !state.matches(config, epoch) {
+            let (chunk_rows, chunk_cols) = config.canvas_chunks();
+            state.epoch = epoch.to_string();
+            state.canvas_size = (config.canvas_width, config.canvas_height);
+            state.chunk_versions = vec![vec![None; chunk_cols]; chunk_rows];
+            state.indices = vec![0; config.canvas_width * config.canvas_height];
+            state.generation += 1;
+        }
+
+        let palette = palette_rgb(config);
+        if state.palette != palette {
+            state.palette = palette;
+            state.generation += 1;
+        }
+
+        for ChunkUpdate { chunk_index, version, chunk } in chunk_updates {
+            let (chunk_index_x, chunk_index_y) = chunk_index;
+            // a concurrent request may already have decoded a newer version
+            let Some(decoded_version) = state.chunk_versions.get_mut(chunk_index_x).and_then(|versions| versions.get_mut(chunk_index_y)) else {
+                continue;
+            };
+            if decoded_version.is_some_and(|decoded_version| decoded_version >= version) {
+                continue;
+            }
+            *decoded_version = Some(version);
+            chunk_color_indices_write(config, &mut state.indices, Region::full(config), chunk_index, &chunk);
+            state.generation += 1;
+        }
+
+        let generation = state.generation;
+        if let Some((_, image)) = state.images.get(&(region, scale)).filter(|(image_generation, _)| *image_generation == generation) {
+            return Ok(image.clone());
+        }
+
+        let mut image = Vec::new();
+        write_png(&mut image, region.width * scale, region.height * scale, &state.palette, &region_scaled(&state.indices, config.canvas_width, region, scale))?;
+        let image = Bytes::from(image);
+
+        if state.images.len() >= PNG_CACHE_IMAGES_MAX {
+            state.images.retain(|_, (image_generation, _)| *image_generation == generation);
+        }
+        if state.images.len() < PNG_CACHE_IMAGES_MAX {
+            state.images.insert((region, scale), (generation, image.clone()));
+        }
+
+        Ok(image)
+    }
+}
+
+/// Render a region from the chunks it holds, without cache
+fn region_png(config: &model::Config, chunk_updates: Vec<ChunkUpdate>, region: Region, scale: usize) -> Result<Bytes, png::EncodingError> {
+    let mut indices = vec![0; region.width * region.height];
+    for ChunkUpdate { chunk_index, chunk, .. } in chunk_updates {
+        chunk_color_indices_write(config, &mut indices, region, chunk_index, &chunk);
+    }
+
+    let region_indices = Region { x: 0, y: 0, ..region };
+    let mut image = Vec::new();
+    write_png(&mut image, region.width * scale, region.height * scale, &palette_rgb(config), &region_scaled(&indices, region.width, region_indices, scale))?;
+    Ok(Bytes::from(image))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn region_chunk_indices() {
+        let config = model::Config::test(16);
+        let region = Region { x: 2, y: 3, width: 2, height: 5 };
+        assert_eq!(region.chunk_indices(&config).collect::<Vec<_>>(), vec![(0, 1), (0, 2), (1, 1), (1, 2)]);
+        assert_eq!(Region::full(&config).chunk_indices(&config).count(), 9);
+    }
+
+    #[test]
+    fn region_png_matches_cached_canvas() {
+        let config = model::Config::test(16);
+        let mut chunks = CanvasChunk::canvas_empty(&config);
+        for (pos_x, pos_y, color) in [(0, 0, 1), (2, 3, 2), (3, 4, 3), (7, 7, 4)] {
+            CanvasChunk::canvas_pixel_write(&config, &mut chunks, pos_x, pos_y, color);
+        }
+        let chunk_updates = |region: Region| region.chunk_indices(&config)
+            .map(|(chunk_index_x, chunk_index_y)| ChunkUpdate { chunk_index: (chunk_index_x, chunk_index_y), version: 1, chunk: chunks[chunk_index_x][chunk_index_y].clone() })
+            .collect::<Vec<_>>();
+
+        let cache = CanvasPngCache::new();
+        for region in [Region::full(&config), Region { x: 2, y: 3, width: 2, height: 5 }] {
+            let cached = cache.canvas_png(&config, "epoch", chunk_updates(Region::full(&config)), region, 2).unwrap();
+            let uncached = region_png(&config, chunk_updates(region), region, 2).unwrap();
+            assert_eq!(cached, uncached);
+        }
+    }
+}
@@ -4,8 +4,12 @@ use crate::controller::*;
 pub fn routes(cfg: &mut web::ServiceConfig) {
     let api_scope = web::scope("/api")
         .service(canvas_get)
+        .service(canvas_png_get)
+        .service(canvas_meta_get)
+        .service(canvas_chunk_get)
         .service(session_get)
         .service(client_timeout)
+        .service(pixel_history_get)
         ;
 
     cfg
@@ -0,0 +1,518 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use futures_util::future::BoxFuture;
+use redb::{Database, MultimapTableDefinition, ReadableTable, ReadableTableMetadata, TableDefinition, WriteTransaction};
+
+use crate::model::{self, BackendError, Client, PixelColorUpdateMessage, PixelHistoryEntry, SharedConfig};
+
+use super::{epoch_new, CanvasChunk, CanvasLayout, CanvasMigration, CanvasSnapshot, CanvasStore, ChunkVersions, Placement, StoreResult};
+
+/// Chunk blobs, keyed like redis chunks
+const CHUNKS_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("chunks");
+/// Chunk versions, keyed like chunks
+const CHUNK_VERSIONS_TABLE: TableDefinition<&str, u64> = TableDefinition::new("chunk_versions");
+/// JSON encoded clients, keyed by session uuid
+const CLIENTS_TABLE: TableDefinition<&str, &str> = TableDefinition::new("clients");
+/// JSON encoded history entries, keyed by increasing entry id, entry id + 1 is the sequence number
+const HISTORY_TABLE: TableDefinition<u64, &str> = TableDefinition::new("history");
+/// History entry ids of each pixel position
+const PIXEL_HISTORY_TABLE: MultimapTableDefinition<(u16, u16), u64> = MultimapTableDefinition::new("pixel_history");
+/// History entry ids of each placement timestamp, lets time ranges seek instead of scanning the history
+const HISTORY_TIMESTAMPS_TABLE: MultimapTableDefinition<u64, u64> = MultimapTableDefinition::new("history_timestamps");
+/// Concatenated snapshot chunks, keyed by snapshot timestamp
+const SNAPSHOTS_TABLE: TableDefinition<u64, &[u8]> = TableDefinition::new("snapshots");
+/// JSON encoded store values, keyed like redis keys
+const META_TABLE: TableDefinition<&str, &str> = TableDefinition::new("meta");
+
+fn db_error<E: Into<redb::Error>>(err: E) -> BackendError {
+    BackendError::from(err.into())
+}
+
+/// Single file store persisted on disk, no separate server needed
+pub struct EmbeddedStore {
+    inner: Arc<EmbeddedDb>,
+    /// Saved on first open, new when the file is deleted
+    epoch: String,
+}
+
+/// Database with its transaction helpers, shared with blocking tasks
+struct EmbeddedDb {
+    config: SharedConfig,
+    db: Database,
+}
+
+impl EmbeddedStore {
+    pub fn open<P: AsRef<Path>>(path: P, config: SharedConfig) -> StoreResult<Self> {
+        let db = Database::create(path).map_err(db_error)?;
+
+        // create tables so read transactions always find them
+        let txn = db.begin_write().map_err(db_error)?;
+        txn.open_table(CHUNKS_TABLE).map_err(db_error)?;
+        txn.open_table(CHUNK_VERSIONS_TABLE).map_err(db_error)?;
+        txn.open_table(CLIENTS_TABLE).map_err(db_error)?;
+        txn.open_table(HISTORY_TABLE).map_err(db_error)?;
+        txn.open_multimap_table(PIXEL_HISTORY_TABLE).map_err(db_error)?;
+        txn.open_multimap_table(HISTORY_TIMESTAMPS_TABLE).map_err(db_error)?;
+        txn.open_table(SNAPSHOTS_TABLE).map_err(db_error)?;
+        txn.open_table(META_TABLE).map_err(db_error)?;
+        EmbeddedDb::history_timestamps_index(&txn)?;
+        txn.commit().map_err(db_error)?;
+
+        let inner = EmbeddedDb { config, db };
+        let epoch = match inner.meta_read(model::EPOCH_DB_KEY)? {
+            Some(epoch) => epoch,
+            None => {
+                let epoch = epoch_new();
+                inner.meta_write(model::EPOCH_DB_KEY, &epoch)?;
+                epoch
+            }
+        };
+
+        Ok(Self { inner: Arc::new(inner), epoch })
+    }
+
+    /// Run a transaction on the blocking pool, redb commits wait for the file to be synced and reads may wait for it too
+    async fn blocking<T, F>(&self, transaction: F) -> StoreResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&EmbeddedDb) -> StoreResult<T> + Send + 'static,
+    {
+        let inner = self.inner.clone();
+        tokio::task::spawn_blocking(move || transaction(&inner)).await
+            .map_err(|err| BackendError::new("Embedded storage transaction failed", err.to_string()))?
+    }
+}
+
+impl EmbeddedDb {
+    fn config(&self) -> Arc<model::Config> {
+        self.config.get()
+    }
+
+    fn meta_read(&self, key: &str) -> StoreResult<Option<String>> {
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let table = txn.open_table(META_TABLE).map_err(db_error)?;
+        let value = table.get(key).map_err(db_error)?.map(|guard| guard.value().to_string());
+        Ok(value)
+    }
+
+    fn meta_write(&self, key: &str, value: &str) -> StoreResult<()> {
+        let txn = self.db.begin_write().map_err(db_error)?;
+        txn.open_table(META_TABLE).map_err(db_error)?.insert(key, value).map_err(db_error)?;
+        txn.commit().map_err(db_error)?;
+        Ok(())
+    }
+
+    fn chunk_read(&self, chunk_key: &str) -> StoreResult<Option<Vec<u8>>> {
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let table = txn.open_table(CHUNKS_TABLE).map_err(db_error)?;
+        let chunk = table.get(chunk_key).map_err(db_error)?.map(|guard| guard.value().to_vec());
+        Ok(chunk)
+    }
+
+    fn chunk_version_read(&self, chunk_key: &str) -> StoreResult<u64> {
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let table = txn.open_table(CHUNK_VERSIONS_TABLE).map_err(db_error)?;
+        let version = table.get(chunk_key).map_err(db_error)?.map(|guard| guard.value());
+        Ok(version.unwrap_or(0))
+    }
+
+    fn chunk_versions_read(&self, chunk_rows: usize, chunk_cols: usize) -> StoreResult<ChunkVersions> {
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let table = txn.open_table(CHUNK_VERSIONS_TABLE).map_err(db_error)?;
+
+        let mut chunk_versions = Vec::with_capacity(chunk_rows);
+        for index_x in 0..chunk_rows {
+            let mut versions = Vec::with_capacity(chunk_cols);
+            for index_y in 0..chunk_cols {
+                let chunk_key = CanvasChunk::chunk_index_to_key(index_x, index_y);
+                versions.push(table.get(chunk_key.as_str()).map_err(db_error)?.map_or(0, |guard| guard.value()));
+            }
+            chunk_versions.push(versions);
+        }
+        Ok(chunk_versions)
+    }
+
+    /// Increment a chunk version inside an already open write transaction
+    fn chunk_version_bump(txn: &WriteTransaction, chunk_key: &str) -> StoreResult<()> {
+        let mut versions = txn.open_table(CHUNK_VERSIONS_TABLE).map_err(db_error)?;
+        let version = versions.get(chunk_key).map_err(db_error)?.map(|guard| guard.value()).unwrap_or(0);
+        versions.insert(chunk_key, version + 1).map_err(db_error)?;
+        Ok(())
+    }
+
+    fn chunk_write(&self, chunk_key: &str, chunk: &[u8]) -> StoreResult<()> {
+        let txn = self.db.begin_write().map_err(db_error)?;
+        txn.open_table(CHUNKS_TABLE).map_err(db_error)?.insert(chunk_key, chunk).map_err(db_error)?;
+        Self::chunk_version_bump(&txn, chunk_key)?;
+        txn.commit().map_err(db_error)?;
+        Ok(())
+    }
+
+    fn chunk_pixel_write(&self, pixel_update: &PixelColorUpdateMessage) -> StoreResult<()> {
+        let (chunk_index, chunk_pos) = self.config().canvas_pos_to_chunk_location(pixel_update.pos_x.into(), pixel_update.pos_y.into());
+        let (chunk_index_x, chunk_index_y) = chunk_index;
+        let chunk_key = CanvasChunk::chunk_index_to_key(chunk_index_x, chunk_index_y);
+
+        let txn = self.db.begin_write().map_err(db_error)?;
+        {
+            let mut table = txn.open_table(CHUNKS_TABLE).map_err(db_error)?;
+            let mut chunk = table.get(chunk_key.as_str()).map_err(db_error)?
+                .map(|guard| guard.value().to_vec())
+                .unwrap_or_else(|| vec![0; CanvasChunk::chunk_byte_size(&self.config())]);
+
+            CanvasChunk::pixel_write(&self.config(), &mut chunk, chunk_pos, pixel_update.color);
+            table.insert(chunk_key.as_str(), chunk.as_slice()).map_err(db_error)?;
+        }
+        Self::chunk_version_bump(&txn, &chunk_key)?;
+        txn.commit().map_err(db_error)?;
+
+        Ok(())
+    }
+
+    fn pixel_place_write(&self, uuid: &str, pixel_update: &PixelColorUpdateMessage) -> StoreResult<Placement> {
+        let (chunk_index, chunk_pos) = self.config().canvas_pos_to_chunk_location(pixel_update.pos_x.into(), pixel_update.pos_y.into());
+        let (chunk_index_x, chunk_index_y) = chunk_index;
+        let chunk_key = CanvasChunk::chunk_index_to_key(chunk_index_x, chunk_index_y);
+
+        // client, chunk and history are all written in a single transaction
+        let txn = self.db.begin_write().map_err(db_error)?;
+        let client = {
+            let mut clients = txn.open_table(CLIENTS_TABLE).map_err(db_error)?;
+            let mut client = match clients.get(uuid).map_err(db_error)? {
+                Some(guard) => Client::decode_json(guard.value())?,
+                None => Client::new(self.config().base_pixel_amount)
+            };
+
+            if !client.take_pixel(&self.config(), Client::timestamp_now()) {
+                return Ok(Placement::NoPixelsLeft(client));
+            }
+            clients.insert(uuid, client.encode_json()?.as_str()).map_err(db_error)?;
+
+            let mut chunks = txn.open_table(CHUNKS_TABLE).map_err(db_error)?;
+            let mut chunk = chunks.get(chunk_key.as_str()).map_err(db_error)?
+                .map(|guard| guard.value().to_vec())
+                .unwrap_or_else(|| vec![0; CanvasChunk::chunk_byte_size(&self.config())]);
+            CanvasChunk::pixel_write(&self.config(), &mut chunk, chunk_pos, pixel_update.color);
+            chunks.insert(chunk_key.as_str(), chunk.as_slice()).map_err(db_error)?;
+
+            client
+        };
+        Self::chunk_version_bump(&txn, &chunk_key)?;
+        let seq = Self::history_write(&txn, &PixelHistoryEntry::new(uuid, pixel_update, Client::timestamp_now()))?;
+        txn.commit().map_err(db_error)?;
+
+        Ok(Placement::Placed(client, seq))
+    }
+
+    fn history_append_write(&self, entry: &PixelHistoryEntry) -> StoreResult<u64> {
+        let txn = self.db.begin_write().map_err(db_error)?;
+        let seq = Self::history_write(&txn, entry)?;
+        txn.commit().map_err(db_error)?;
+        Ok(seq)
+    }
+
+    /// Append a history entry inside an already open write transaction, returns its sequence number
+    fn history_write(txn: &WriteTransaction, entry: &PixelHistoryEntry) -> StoreResult<u64> {
+        let mut history = txn.open_table(HISTORY_TABLE).map_err(db_error)?;
+        let entry_id = match history.last().map_err(db_error)? {
+            Some((last_id, _)) => last_id.value() + 1,
+            None => 0
+        };
+        history.insert(entry_id, entry.encode_json()?.as_str()).map_err(db_error)?;
+
+        let mut pixel_history = txn.open_multimap_table(PIXEL_HISTORY_TABLE).map_err(db_error)?;
+        pixel_history.insert((entry.pos_x, entry.pos_y), entry_id).map_err(db_error)?;
+
+        let mut history_timestamps = txn.open_multimap_table(HISTORY_TIMESTAMPS_TABLE).map_err(db_error)?;
+        history_timestamps.insert(entry.timestamp, entry_id).map_err(db_error)?;
+
+        Ok(entry_id + 1)
+    }
+
+    /// Index history written before timestamps were indexed
+    fn history_timestamps_index(txn: &WriteTransaction) -> StoreResult<()> {
+        let history = txn.open_table(HISTORY_TABLE).map_err(db_error)?;
+        let mut history_timestamps = txn.open_multimap_table(HISTORY_TIMESTAMPS_TABLE).map_err(db_error)?;
+        if history.is_empty().map_err(db_error)? || !history_timestamps.is_empty().map_err(db_error)? {
+            return Ok(());
+        }
+
+        for item in history.iter().map_err(db_error)? {
+            let (entry_id, guard) = item.map_err(db_error)?;
+            let entry = PixelHistoryEntry::decode_json(guard.value())?;
+            history_timestamps.insert(entry.timestamp, entry_id.value()).map_err(db_error)?;
+        }
+
+        Ok(())
+    }
+
+    fn history_seq_read(&self) -> StoreResult<u64> {
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let history = txn.open_table(HISTORY_TABLE).map_err(db_error)?;
+        let last_id = history.last().map_err(db_error)?.map(|(last_id, _)| last_id.value());
+        Ok(last_id.map_or(0, |last_id| last_id + 1))
+    }
+
+    fn pixel_history_read(&self, pos_x: u16, pos_y: u16) -> StoreResult<Vec<PixelHistoryEntry>> {
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let history = txn.open_table(HISTORY_TABLE).map_err(db_error)?;
+        let pixel_history = txn.open_multimap_table(PIXEL_HISTORY_TABLE).map_err(db_error)?;
+
+        let mut entries = Vec::new();
+        for entry_id in pixel_history.get((pos_x, pos_y)).map_err(db_error)? {
+            let entry_id = entry_id.map_err(db_error)?.value();
+            if let Some(guard) = history.get(entry_id).map_err(db_error)? {
+                entries.push(PixelHistoryEntry::decode_json(guard.value())?);
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn history_range_read(&self, since: u64, until: u64) -> StoreResult<Vec<PixelHistoryEntry>> {
+        if since > until {
+            return Ok(Vec::new());
+        }
+
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let history = txn.open_table(HISTORY_TABLE).map_err(db_error)?;
+        let history_timestamps = txn.open_multimap_table(HISTORY_TIMESTAMPS_TABLE).map_err(db_error)?;
+
+        let mut entry_ids = Vec::new();
+        for item in history_timestamps.range(since..=until).map_err(db_error)? {
+            let (_, ids) = item.map_err(db_error)?;
+            for entry_id in ids {
+                entry_ids.push(entry_id.map_err(db_error)?.value());
+            }
+        }
+        // placement order, timestamps may go back when the clock does
+        entry_ids.sort_unstable();
+
+        let mut entries = Vec::with_capacity(entry_ids.len());
+        for entry_id in entry_ids {
+            if let Some(guard) = history.get(entry_id).map_err(db_error)? {
+                entries.push(PixelHistoryEntry::decode_json(guard.value())?);
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn snapshot_write(&self, snapshot: &CanvasSnapshot) -> StoreResult<()> {
+        let txn = self.db.begin_write().map_err(db_error)?;
+        txn.open_table(SNAPSHOTS_TABLE).map_err(db_error)?
+            .insert(snapshot.timestamp, snapshot.to_bytes().as_slice()).map_err(db_error)?;
+        txn.commit().map_err(db_error)?;
+        Ok(())
+    }
+
+    fn snapshot_read(&self, timestamp: u64) -> StoreResult<Option<CanvasSnapshot>> {
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let snapshots = txn.open_table(SNAPSHOTS_TABLE).map_err(db_error)?;
+
+        let latest = snapshots.range(..=timestamp).map_err(db_error)?.next_back();
+        match latest {
+            Some(item) => {
+                let (snapshot_timestamp, bytes) = item.map_err(db_error)?;
+                CanvasSnapshot::from_bytes(snapshot_timestamp.value(), bytes.value()).map(Some)
+            },
+            None => Ok(None)
+        }
+    }
+
+    fn client_read(&self, uuid: &str) -> StoreResult<Option<String>> {
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let table = txn.open_table(CLIENTS_TABLE).map_err(db_error)?;
+        let client_string = table.get(uuid).map_err(db_error)?.map(|guard| guard.value().to_string());
+        Ok(client_string)
+    }
+
+    fn client_write(&self, uuid: &str, client_string: &str) -> StoreResult<()> {
+        let txn = self.db.begin_write().map_err(db_error)?;
+        txn.open_table(CLIENTS_TABLE).map_err(db_error)?.insert(uuid, client_string).map_err(db_error)?;
+        txn.commit().map_err(db_error)?;
+        Ok(())
+    }
+}
+
+impl CanvasStore for EmbeddedStore {
+    fn config(&self) -> Arc<model::Config> {
+        self.inner.config()
+    }
+
+    fn layout_get(&self) -> BoxFuture<'_, StoreResult<Option<CanvasLayout>>> {
+        Box::pin(async move {
+            match self.blocking(|db| db.meta_read(model::LAYOUT_DB_KEY)).await? {
+                Some(layout_string) => Ok(Some(serde_json::from_str(&layout_string)?)),
+                None => Ok(None)
+            }
+        })
+    }
+
+    fn layout_set<'a>(&'a self, layout: &'a CanvasLayout) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            let layout_string = serde_json::to_string(layout)?;
+            self.blocking(move |db| db.meta_write(model::LAYOUT_DB_KEY, &layout_string)).await
+        })
+    }
+
+    fn migration_get(&self) -> BoxFuture<'_, StoreResult<Option<CanvasMigration>>> {
+        Box::pin(async move {
+            match self.blocking(|db| db.meta_read(model::MIGRATION_DB_KEY)).await? {
+                Some(migration_string) => Ok(Some(serde_json::from_str(&migration_string)?)),
+                None => Ok(None)
+            }
+        })
+    }
+
+    fn migration_set<'a>(&'a self, migration: &'a CanvasMigration) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            let migration_string = serde_json::to_string(migration)?;
+            self.blocking(move |db| db.meta_write(model::MIGRATION_DB_KEY, &migration_string)).await
+        })
+    }
+
+    fn chunk_epoch(&self) -> BoxFuture<'_, StoreResult<String>> {
+        Box::pin(async move {
+            Ok(self.epoch.clone())
+        })
+    }
+
+    fn chunk_get(&self, chunk_index_x: usize, chunk_index_y: usize) -> BoxFuture<'_, StoreResult<Vec<u8>>> {
+        Box::pin(async move {
+            let chunk_key = CanvasChunk::chunk_index_to_key(chunk_index_x, chunk_index_y);
+            let chunk_size = CanvasChunk::chunk_byte_size(&self.config());
+
+            let mut colors = self.blocking(move |db| db.chunk_read(&chunk_key)).await?
+                .unwrap_or_else(|| vec![0; chunk_size]);
+
+            colors.resize(chunk_size, 0);
+            Ok(colors)
+        })
+    }
+
+    fn chunk_exists(&self, chunk_index_x: usize, chunk_index_y: usize) -> BoxFuture<'_, StoreResult<bool>> {
+        Box::pin(async move {
+            let chunk_key = CanvasChunk::chunk_index_to_key(chunk_index_x, chunk_index_y);
+            Ok(self.blocking(move |db| db.chunk_read(&chunk_key)).await?.is_some())
+        })
+    }
+
+    fn chunk_version(&self, chunk_index_x: usize, chunk_index_y: usize) -> BoxFuture<'_, StoreResult<(String, u64)>> {
+        Box::pin(async move {
+            let chunk_key = CanvasChunk::chunk_index_to_key(chunk_index_x, chunk_index_y);
+            let version = self.blocking(move |db| db.chunk_version_read(&chunk_key)).await?;
+            Ok((self.epoch.clone(), version))
+        })
+    }
+
+    fn chunk_versions(&self) -> BoxFuture<'_, StoreResult<(String, ChunkVersions)>> {
+        Box::pin(async move {
+            let (chunk_rows, chunk_cols) = self.config().canvas_chunks();
+            let versions = self.blocking(move |db| db.chunk_versions_read(chunk_rows, chunk_cols)).await?;
+            Ok((self.epoch.clone(), versions))
+        })
+    }
+
+    fn chunk_set<'a>(&'a self, chunk_index_x: usize, chunk_index_y: usize, chunk: &'a [u8]) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            let chunk_key = CanvasChunk::chunk_index_to_key(chunk_index_x, chunk_index_y);
+            let chunk = chunk.to_vec();
+            self.blocking(move |db| db.chunk_write(&chunk_key, &chunk)).await
+        })
+    }
+
+    fn pixel_set<'a>(&'a self, pixel_update: &'a PixelColorUpdateMessage) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            let pixel_update = pixel_update.clone();
+            self.blocking(move |db| db.chunk_pixel_write(&pixel_update)).await
+        })
+    }
+
+    fn pixel_place<'a>(&'a self, uuid: &'a str, pixel_update: &'a PixelColorUpdateMessage) -> BoxFuture<'a, StoreResult<Placement>> {
+        Box::pin(async move {
+            let uuid = uuid.to_string();
+            let pixel_update = pixel_update.clone();
+            self.blocking(move |db| db.pixel_place_write(&uuid, &pixel_update)).await
+        })
+    }
+
+    fn history_append<'a>(&'a self, entry: &'a PixelHistoryEntry) -> BoxFuture<'a, StoreResult<u64>> {
+        Box::pin(async move {
+            let entry = entry.clone();
+            self.blocking(move |db| db.history_append_write(&entry)).await
+        })
+    }
+
+    fn history_seq(&self) -> BoxFuture<'_, StoreResult<u64>> {
+        Box::pin(async move {
+            self.blocking(|db| db.history_seq_read()).await
+        })
+    }
+
+    fn pixel_history(&self, pos_x: u16, pos_y: u16) -> BoxFuture<'_, StoreResult<Vec<PixelHistoryEntry>>> {
+        Box::pin(async move {
+            self.blocking(move |db| db.pixel_history_read(pos_x, pos_y)).await
+        })
+    }
+
+    fn history_range(&self, since: u64, until: u64) -> BoxFuture<'_, StoreResult<Vec<PixelHistoryEntry>>> {
+        Box::pin(async move {
+            self.blocking(move |db| db.history_range_read(since, until)).await
+        })
+    }
+
+    fn snapshot_save<'a>(&'a self, snapshot: &'a CanvasSnapshot) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            let snapshot = snapshot.clone();
+            self.blocking(move |db| db.snapshot_write(&snapshot)).await
+        })
+    }
+
+    fn snapshot_get(&self, timestamp: u64) -> BoxFuture<'_, StoreResult<Option<CanvasSnapshot>>> {
+        Box::pin(async move {
+            self.blocking(move |db| db.snapshot_read(timestamp)).await
+        })
+    }
+
+    fn client_get<'a>(&'a self, uuid: &'a str) -> BoxFuture<'a, StoreResult<Option<Client>>> {
+        Box::pin(async move {
+            let uuid = uuid.to_string();
+            match self.blocking(move |db| db.client_read(&uuid)).await? {
+                Some(client_string) => Ok(Some(Client::decode_json(client_string)?)),
+                None => Ok(None)
+            }
+        })
+    }
+
+    fn client_set<'a>(&'a self, uuid: &'a str, client: &'a Client) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            let client_string = client.encode_json()?;
+            let uuid = uuid.to_string();
+            self.blocking(move |db| db.client_write(&uuid, &client_string)).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempPath;
+
+    #[tokio::test]
+    async fn history_range_in_placement_order() {
+        // declared first, the store is closed before the file is removed
+        let path = TempPath::new("embedded-store-test.redb");
+        let store = EmbeddedStore::open(path.path(), model::Config::test(4).into()).unwrap();
+
+        for (pos_x, timestamp) in [(0, 200), (1, 100), (2, 150), (3, 300)] {
+            let pixel_update = PixelColorUpdateMessage { pos_x, pos_y: 0, color: 1 };
+            store.history_append(&PixelHistoryEntry::new("author", &pixel_update, timestamp)).await.unwrap();
+        }
+
+        let entries = store.history_range(100, 200).await.unwrap();
+        assert_eq!(entries.iter().map(|entry| entry.pos_x).collect::<Vec<_>>(), [0, 1, 2]);
+        assert!(store.history_range(201, 299).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use futures_util::future::BoxFuture;
+
+use crate::model::{self, Client, PixelColorUpdateMessage, PixelHistoryEntry, SharedConfig};
+
+use super::{epoch_new, CanvasChunk, CanvasLayout, CanvasMigration, CanvasSnapshot, CanvasStore, ChunkVersions, Placement, StoreResult};
+
+/// In-process store, everything is lost when the server stops
+pub struct MemoryStore {
+    config: SharedConfig,
+    /// Picked at startup, as nothing outlives the process
+    epoch: String,
+    layout: Mutex<Option<CanvasLayout>>,
+    migration: Mutex<Option<CanvasMigration>>,
+    chunks: Mutex<HashMap<(usize, usize), Vec<u8>>>,
+    chunk_versions: Mutex<HashMap<(usize, usize), u64>>,
+    clients: Mutex<HashMap<String, Client>>,
+    history: Mutex<Vec<PixelHistoryEntry>>,
+    snapshots: Mutex<Vec<CanvasSnapshot>>,
+}
+
+impl MemoryStore {
+    pub fn new(config: SharedConfig) -> Self {
+        Self {
+            config,
+            epoch: epoch_new(),
+            layout: Mutex::new(None),
+            migration: Mutex::new(None),
+            chunks: Mutex::new(HashMap::new()),
+            chunk_versions: Mutex::new(HashMap::new()),
+            clients: Mutex::new(HashMap::new()),
+            history: Mutex::new(Vec::new()),
+            snapshots: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl CanvasStore for MemoryStore {
+    fn config(&self) -> Arc<model::Config> {
+        self.config.get()
+    }
+
+    fn layout_get(&self) -> BoxFuture<'_, StoreResult<Option<CanvasLayout>>> {
+        Box::pin(async move {
+            Ok(*self.layout.lock().unwrap())
+        })
+    }
+
+    fn layout_set<'a>(&'a self, layout: &'a CanvasLayout) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            *self.layout.lock().unwrap() = Some(*layout);
+            Ok(())
+        })
+    }
+
+    fn migration_get(&self) -> BoxFuture<'_, StoreResult<Option<CanvasMigration>>> {
+        Box::pin(async move {
+            Ok(*self.migration.lock().unwrap())
+        })
+    }
+
+    fn migration_set<'a>(&'a self, migration: &'a CanvasMigration) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            *self.migration.lock().unwrap() = Some(*migration);
+            Ok(())
+        })
+    }
+
+    fn chunk_epoch(&self) -> BoxFuture<'_, StoreResult<String>> {
+        Box::pin(async move {
+            Ok(self.epoch.clone())
+        })
+    }
+
+    fn chunk_get(&self, chunk_index_x: usize, chunk_index_y: usize) -> BoxFuture<'_, StoreResult<Vec<u8>>> {
+        Box::pin(async move {
+            let mut chunks = self.chunks.lock().unwrap();
+            let chunk = chunks.entry((chunk_index_x, chunk_index_y))
+                .or_insert_with(|| vec![0; CanvasChunk::chunk_byte_size(&self.config())]);
+
+            Ok(chunk.clone())
+        })
+    }
+
+    fn chunk_exists(&self, chunk_index_x: usize, chunk_index_y: usize) -> BoxFuture<'_, StoreResult<bool>> {
+        Box::pin(async move {
+            Ok(self.chunks.lock().unwrap().contains_key(&(chunk_index_x, chunk_index_y)))
+        })
+    }
+
+    fn chunk_version(&self, chunk_index_x: usize, chunk_index_y: usize) -> BoxFuture<'_, StoreResult<(String, u64)>> {
+        Box::pin(async move {
+            let version = self.chunk_versions.lock().unwrap().get(&(chunk_index_x, chunk_index_y)).copied().unwrap_or(0);
+            Ok((self.epoch.clone(), version))
+        })
+    }
+
+    fn chunk_versions(&self) -> BoxFuture<'_, StoreResult<(String, ChunkVersions)>> {
+        Box::pin(async move {
+            let (chunk_rows, chunk_cols) = self.config().canvas_chunks();
+            let chunk_versions = self.chunk_versions.lock().unwrap();
+
+            let versions = (0..chunk_rows)
+                .map(|index_x| (0..chunk_cols).map(|index_y| chunk_versions.get(&(index_x, index_y)).copied().unwrap_or(0)).collect())
+                .collect();
+            Ok((self.epoch.clone(), versions))
+        })
+    }
+
+    fn chunk_set<'a>(&'a self, chunk_index_x: usize, chunk_index_y: usize, chunk: &'a [u8]) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            self.chunks.lock().unwrap().insert((chunk_index_x, chunk_index_y), chunk.to_vec());
+            *self.chunk_versions.lock().unwrap().entry((chunk_index_x, chunk_index_y)).or_insert(0) += 1;
+
+            Ok(())
+        })
+    }
+
+    fn pixel_set<'a>(&'a self, pixel_update: &'a PixelColorUpdateMessage) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            let (chunk_index, chunk_pos) = self.config().canvas_pos_to_chunk_location(pixel_update.pos_x.into(), pixel_update.pos_y.into());
+
+            let mut chunks = self.chunks.lock().unwrap();
+            let chunk = chunks.entry(chunk_index)
+                .or_insert_with(|| vec![0; CanvasChunk::chunk_byte_size(&self.config())]);
+
+            CanvasChunk::pixel_write(&self.config(), chunk, chunk_pos, pixel_update.color);
+            *self.chunk_versions.lock().unwrap().entry(chunk_index).or_insert(0) += 1;
+
+            Ok(())
+        })
+    }
+
+    fn pixel_place<'a>(&'a self, uuid: &'a str, pixel_update: &'a PixelColorUpdateMessage) -> BoxFuture<'a, StoreResult<Placement>> {
+        Box::pin(async move {
+            // hold clients lock for the whole placement
+            let mut clients = self.clients.lock().unwrap();
+            let client = clients.entry(uuid.to_string())
+                .or_insert_with(|| Client::new(self.config().base_pixel_amount));
+
+            if !client.take_pixel(&self.config(), Client::timestamp_now()) {
+                return Ok(Placement::NoPixelsLeft(client.clone()));
+            }
+
+            let (chunk_index, chunk_pos) = self.config().canvas_pos_to_chunk_location(pixel_update.pos_x.into(), pixel_update.pos_y.into());
+            let mut chunks = self.chunks.lock().unwrap();
+            let chunk = chunks.entry(chunk_index)
+                .or_insert_with(|| vec![0; CanvasChunk::chunk_byte_size(&self.config())]);
+            CanvasChunk::pixel_write(&self.config(), chunk, chunk_pos, pixel_update.color);
+            *self.chunk_versions.lock().unwrap().entry(chunk_index).or_insert(0) += 1;
+
+            let mut history = self.history.lock().unwrap();
+            history.push(PixelHistoryEntry::new(uuid, pixel_update, Client::timestamp_now()));
+
+            Ok(Placement::Placed(client.clone(), history.len() as u64))
+        })
+    }
+
+    fn history_append<'a>(&'a self, entry: &'a PixelHistoryEntry) -> BoxFuture<'a, StoreResult<u64>> {
+        Box::pin(async move {
+            let mut history = self.history.lock().unwrap();
+            history.push(entry.clone());
+            Ok(history.len() as u64)
+        })
+    }
+
+    fn history_seq(&self) -> BoxFuture<'_, StoreResult<u64>> {
+        Box::pin(async move {
+            Ok(self.history.lock().unwrap().len() as u64)
+        })
+    }
+
+    fn pixel_history(&self, pos_x: u16, pos_y: u16) -> BoxFuture<'_, StoreResult<Vec<PixelHistoryEntry>>> {
+        Box::pin(async move {
+            Ok(self.history.lock().unwrap().iter()
+                .filter(|entry| entry.pos_x == pos_x && entry.pos_y == pos_y)
+                .cloned()
+                .collect())
+        })
+    }
+
+    fn history_range(&self, since: u64, until: u64) -> BoxFuture<'_, StoreResult<Vec<PixelHistoryEntry>>> {
+        Box::pin(async move {
+            Ok(self.history.lock().unwrap().iter()
+                .filter(|entry| entry.timestamp >= since && entry.timestamp <= until)
+                .cloned()
+                .collect())
+        })
+    }
+
+    fn snapshot_save<'a>(&'a self, snapshot: &'a CanvasSnapshot) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            self.snapshots.lock().unwrap().push(snapshot.clone());
+            Ok(())
+        })
+    }
+
+    fn snapshot_get(&self, timestamp: u64) -> BoxFuture<'_, StoreResult<Option<CanvasSnapshot>>> {
+        Box::pin(async move {
+            Ok(self.snapshots.lock().unwrap().iter()
+                .filter(|snapshot| snapshot.timestamp <= timestamp)
+                .max_by_key(|snapshot| snapshot.timestamp)
+                .cloned())
+        })
+    }
+
+    fn client_get<'a>(&'a self, uuid: &'a str) -> BoxFuture<'a, StoreResult<Option<Client>>> {
+        Box::pin(async move {
+            Ok(self.clients.lock().unwrap().get(uuid).cloned())
+        })
+    }
+
+    fn client_set<'a>(&'a self, uuid: &'a str, client: &'a Client) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            self.clients.lock().unwrap().insert(uuid.to_string(), client.clone());
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> MemoryStore {
+        MemoryStore::new(model::Config::test(16).into())
+    }
+
+    fn pixel(pos_x: u16, pos_y: u16, color: u8) -> PixelColorUpdateMessage {
+        PixelColorUpdateMessage { pos_x, pos_y, color }
+    }
+
+    fn history_entry(pos_x: u16, pos_y: u16, color: u8, timestamp: u64) -> PixelHistoryEntry {
+        PixelHistoryEntry::new("author", &pixel(pos_x, pos_y, color), timestamp)
+    }
+
+    #[tokio::test]
+    async fn pixel_place_spends_quota_and_numbers_placements() {
+        let store = store();
+
+        for (seq, remaining_pixels) in [(1, 2), (2, 1), (3, 0)] {
+            let Placement::Placed(client, placement_seq) = store.pixel_place("a", &pixel(seq as u16, 0, 5)).await.unwrap() else {
+                panic!("placement {seq} refused");
+            };
+            assert_eq!((placement_seq, client.remaining_pixels), (seq, remaining_pixels));
+        }
+
+        // nothing written without pixels left
+        let mut chunk = vec![0; CanvasChunk::chunk_byte_size(&store.config())];
+        let placement = store.pixel_place("a", &pixel(7, 7, 5)).await.unwrap();
+        assert!(matches!(placement, Placement::NoPixelsLeft(_)));
+        assert_eq!(store.history_seq().await.unwrap(), 3);
+        assert_eq!(store.chunk_get(2, 2).await.unwrap(), chunk);
+
+        // quota is per client, sequence numbers are global
+        assert!(matches!(store.pixel_place("b", &pixel(7, 7, 9)).await.unwrap(), Placement::Placed(_, 4)));
+        CanvasChunk::pixel_write(&store.config(), &mut chunk, (1, 1), 9);
+        assert_eq!(store.chunk_get(2, 2).await.unwrap(), chunk);
+        assert_eq!(store.client_get("a").await.unwrap().unwrap().remaining_pixels, 0);
+    }
+
+    #[tokio::test]
+    async fn history_keeps_placement_order() {
+        let store = store();
+        store.pixel_place("a", &pixel(1, 1, 3)).await.unwrap();
+        store.pixel_place("b", &pixel(2, 1, 4)).await.unwrap();
+        store.pixel_place("b", &pixel(1, 1, 5)).await.unwrap();
+
+        let pixel_history = store.pixel_history(1, 1).await.unwrap();
+        let colors: Vec<_> = pixel_history.iter().map(|entry| (entry.uuid.as_str(), entry.color)).collect();
+        assert_eq!(colors, vec![("a", 3), ("b", 5)]);
+        assert!(store.pixel_history(3, 3).await.unwrap().is_empty());
+
+        let history = store.history_range(0, u64::MAX).await.unwrap();
+        let positions: Vec<_> = history.iter().map(|entry| (entry.pos_x, entry.pos_y)).collect();
+        assert_eq!(positions, vec![(1, 1), (2, 1), (1, 1)]);
+        assert!(store.history_range(0, 1).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn canvas_get_at_replays_history_over_snapshot() {
+        let store = store();
+        let config = &store.config();
+
+        let mut chunks = CanvasChunk::canvas_empty(config);
+        CanvasChunk::canvas_pixel_write(config, &mut chunks, 0, 0, 1);
+        store.snapshot_save(&CanvasSnapshot::new(config, 100, chunks)).await.unwrap();
+        store.history_append(&history_entry(0, 0, 2, 150)).await.unwrap();
+        store.history_append(&history_entry(4, 4, 3, 150)).await.unwrap();
+        store.history_append(&history_entry(0, 0, 4, 250)).await.unwrap();
+
+        let canvas_with = |pixels: &[(usize, usize, u8)]| {
+            let mut chunks = CanvasChunk::canvas_empty(config);
+            for &(pos_x, pos_y, color) in pixels {
+                CanvasChunk::canvas_pixel_write(config, &mut chunks, pos_x, pos_y, color);
+            }
+            chunks
+        };
+
+        assert_eq!(store.canvas_get_at(50).await.unwrap(), canvas_with(&[]));
+        assert_eq!(store.canvas_get_at(100).await.unwrap(), canvas_with(&[(0, 0, 1)]));
+        assert_eq!(store.canvas_get_at(200).await.unwrap(), canvas_with(&[(0, 0, 2), (4, 4, 3)]));
+        assert_eq!(store.canvas_get_at(u64::MAX).await.unwrap(), canvas_with(&[(0, 0, 4), (4, 4, 3)]));
+    }
+
+    #[tokio::test]
+    async fn chunk_version_bumped_by_writes_of_its_chunk() {
+        let store = store();
+        let epoch = store.chunk_epoch().await.unwrap();
+        let version = |version: u64| (epoch.clone(), version);
+        assert_eq!(store.chunk_version(0, 0).await.unwrap(), version(0));
+
+        store.pixel_place("a", &pixel(1, 1, 3)).await.unwrap();
+        store.pixel_set(&pixel(2, 1, 3)).await.unwrap();
+        assert_eq!(store.chunk_version(0, 0).await.unwrap(), version(2));
+        assert_eq!(store.chunk_version(1, 0).await.unwrap(), version(0));
+
+        store.pixel_set(&pixel(3, 0, 3)).await.unwrap();
+        assert_eq!(store.chunk_version(1, 0).await.unwrap(), version(1));
+        assert_eq!(store.chunk_version(0, 0).await.unwrap(), version(2));
+
+        let (versions_epoch, versions) = store.chunk_versions().await.unwrap();
+        assert_eq!(versions_epoch, epoch);
+        assert_eq!(versions, vec![vec![2, 0, 0], vec![1, 0, 0], vec![0, 0, 0]]);
+    }
+}
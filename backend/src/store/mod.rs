@@ -0,0 +1,565 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::model::{self, BackendError, Client, PixelColorUpdateMessage, PixelHistoryEntry, SharedConfig, StorageKind};
+
+mod redis_store;
+pub use redis_store::*;
+
+mod memory_store;
+pub use memory_store::*;
+
+mod embedded_store;
+pub use embedded_store::*;
+
+pub type StoreResult<T> = Result<T, BackendError>;
+
+/// Canvas chunks indexed by `[chunk_index_x][chunk_index_y]`
+pub type CanvasChunks = Vec<Vec<Vec<u8>>>;
+
+/// Chunk versions indexed by `[chunk_index_x][chunk_index_y]`
+pub type ChunkVersions = Vec<Vec<u64>>;
+
+/// Whole canvas saved at a given time, base of canvas time travel
+#[derive(Debug, Clone)]
+pub struct CanvasSnapshot {
+    pub timestamp: u64,
+    /// Layout of the chunks, snapshots taken before a migration cannot be read with the current one
+    pub layout: CanvasLayout,
+    pub chunks: CanvasChunks,
+}
+
+/// Snapshot bytes header: bits per pixel, then chunk size, chunk rows and chunk columns as big endian u32
+const SNAPSHOT_HEADER_SIZE: usize = 13;
+
+impl CanvasSnapshot {
+    pub fn new(config: &model::Config, timestamp: u64, chunks: CanvasChunks) -> Self {
+        Self { timestamp, layout: CanvasLayout::new(config), chunks }
+    }
+
+    /// Layout header followed by chunks concatenated in `[chunk_index_x][chunk_index_y]` order
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let chunk_rows = self.chunks.len();
+        let chunk_cols = self.chunks.first().map_or(0, Vec::len);
+
+        let mut bytes = Vec::with_capacity(SNAPSHOT_HEADER_SIZE + chunk_rows * chunk_cols * self.layout.chunk_byte_size());
+        bytes.push(self.layout.bits_per_pixel as u8);
+        for value in [self.layout.chunk_size, chunk_rows, chunk_cols] {
+            bytes.extend((value as u32).to_be_bytes());
+        }
+        bytes.extend(self.chunks.iter().flatten().flatten());
+        bytes
+    }
+
+    /// Fails on snapshots saved without a layout header by older versions
+    pub fn from_bytes(timestamp: u64, bytes: &[u8]) -> StoreResult<Self> {
+        let invalid = || BackendError::new(
+            "Invalid canvas snapshot",
+            format!("Snapshot at {timestamp} has no valid layout header, it was saved by an older version"),
+        );
+
+        let (header, chunk_bytes) = bytes.split_first_chunk::<SNAPSHOT_HEADER_SIZE>().ok_or_else(invalid)?;
+        let header_value = |offset: usize| u32::from_be_bytes([header[offset], header[offset + 1], header[offset + 2], header[offset + 3]]) as usize;
+        let layout = CanvasLayout { bits_per_pixel: header[0].into(), chunk_size: header_value(1) };
+        let (chunk_rows, chunk_cols) = (header_value(5), header_value(9));
+
+        let chunk_byte_size = layout.chunk_byte_size();
+        if chunk_byte_size == 0 || chunk_bytes.len() != chunk_rows * chunk_cols * chunk_byte_size {
+            return Err(invalid());
+        }
+
+        let mut chunk_iter = chunk_bytes.chunks_exact(chunk_byte_size).map(<[u8]>::to_vec);
+        let chunks = (0..chunk_rows).map(|_| chunk_iter.by_ref().take(chunk_cols).collect()).collect();
+
+        Ok(Self { timestamp, layout, chunks })
+    }
+
+    /// Fail unless the snapshot chunks can be read with config
+    pub fn layout_check(&self, config: &model::Config) -> StoreResult<()> {
+        let layout = CanvasLayout::new(config);
+        let chunk_grid = (self.chunks.len(), self.chunks.first().map_or(0, Vec::len));
+        if self.layout != layout || chunk_grid != config.canvas_chunks() {
+            return Err(BackendError::new(
+                "Time travel before a migration is not supported",
+                format!(
+                    "Snapshot at {} is stored with {} over {}x{} chunks, canvas uses {} over {}x{} chunks",
+                    self.timestamp, self.layout, chunk_grid.0, chunk_grid.1,
+                    layout, config.canvas_chunks().0, config.canvas_chunks().1,
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Encoding of stored chunks, they cannot be read with another one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CanvasLayout {
+    pub bits_per_pixel: usize,
+    pub chunk_size: usize,
+}
+
+impl CanvasLayout {
+    pub fn new(config: &model::Config) -> Self {
+        Self { bits_per_pixel: config.bits_per_pixel(), chunk_size: config.canvas_chunk_size }
+    }
+
+    /// Layout of chunks written before layouts were saved, pixels always had 4 bits then
+    pub fn legacy(config: &model::Config) -> Self {
+        Self { bits_per_pixel: 4, chunk_size: config.canvas_chunk_size }
+    }
+
+    /// Size in bytes of a whole chunk
+    pub fn chunk_byte_size(&self) -> usize {
+        (self.chunk_size * self.chunk_size * self.bits_per_pixel).div_ceil(8)
+    }
+}
+
+impl fmt::Display for CanvasLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bits per pixel and chunks of {}", self.bits_per_pixel, self.chunk_size)
+    }
+}
+
+/// Latest layout change, history placed before it cannot be replayed with the current layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CanvasMigration {
+    pub timestamp: u64,
+    pub layout: CanvasLayout,
+}
+
+impl CanvasMigration {
+    /// Fail if timestamp is before the migration
+    pub fn check(&self, timestamp: u64) -> StoreResult<()> {
+        if timestamp < self.timestamp {
+            return Err(BackendError::new(
+                "Time travel before a migration is not supported",
+                format!(
+                    "Canvas was migrated to {} at {}, no snapshot with that layout covers {}",
+                    self.layout, self.timestamp, timestamp,
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Outcome of a pixel placement
+#[derive(Debug)]
+pub enum Placement {
+    /// Pixel written, with the updated client and the placement sequence number
+    Placed(Client, u64),
+    /// Client has no pixels left, nothing written
+    NoPixelsLeft(Client),
+}
+
+impl Placement {
+    pub fn client(&self) -> &Client {
+        match self {
+            Self::Placed(client, _) | Self::NoPixelsLeft(client) => client,
+        }
+    }
+}
+
+/// Persistence of canvas chunks and client records, shared by `PlaceServer` and the controllers
+pub trait CanvasStore: Send + Sync {
+    /// Current config, chunk layout stays the same across reloads
+    fn config(&self) -> Arc<model::Config>;
+
+    /// Layout chunks were written with, `None` if never saved
+    fn layout_get(&self) -> BoxFuture<'_, StoreResult<Option<CanvasLayout>>>;
+
+    /// Save the layout chunks are written with
+    fn layout_set<'a>(&'a self, layout: &'a CanvasLayout) -> BoxFuture<'a, StoreResult<()>>;
+
+    /// Latest migration, `None` if the canvas was never migrated
+    fn migration_get(&self) -> BoxFuture<'_, StoreResult<Option<CanvasMigration>>>;
+
+    /// Save the latest migration
+    fn migration_set<'a>(&'a self, migration: &'a CanvasMigration) -> BoxFuture<'a, StoreResult<()>>;
+
+    /// Get chunk bytes at the given chunk index, creating an empty chunk if missing
+    fn chunk_get(&self, chunk_index_x: usize, chunk_index_y: usize) -> BoxFuture<'_, StoreResult<Vec<u8>>>;
+
+    /// Whether the chunk was ever written
+    fn chunk_exists(&self, chunk_index_x: usize, chunk_index_y: usize) -> BoxFuture<'_, StoreResult<bool>>;
+
+    /// Get the epoch and the chunk version in a single store read, the version is bumped on every write of the chunk, 0 if never written
+    fn chunk_version(&self, chunk_index_x: usize, chunk_index_y: usize) -> BoxFuture<'_, StoreResult<(String, u64)>>;
+
+    /// Random id renewed whenever stored chunks are lost, chunk versions only compare within one epoch
+    fn chunk_epoch(&self) -> BoxFuture<'_, StoreResult<String>>;
+
+    /// Get the epoch and the version of every chunk in a single store read
+    fn chunk_versions(&self) -> BoxFuture<'_, StoreResult<(String, ChunkVersions)>>;
+
+    /// Replace a whole chunk and bump its version
+    fn chunk_set<'a>(&'a self, chunk_index_x: usize, chunk_index_y: usize, chunk: &'a [u8]) -> BoxFuture<'a, StoreResult<()>>;
+
+    /// Write the color bits of a single pixel
+    fn pixel_set<'a>(&'a self, pixel_update: &'a PixelColorUpdateMessage) -> BoxFuture<'a, StoreResult<()>>;
+
+    /// Get a client record, `None` if this uuid was never saved
+    fn client_get<'a>(&'a self, uuid: &'a str) -> BoxFuture<'a, StoreResult<Option<Client>>>;
+
+    /// Save a client record
+    fn client_set<'a>(&'a self, uuid: &'a str, client: &'a Client) -> BoxFuture<'a, StoreResult<()>>;
+
+    /// Append an accepted placement to the history log, returns its sequence number
+    fn history_append<'a>(&'a self, entry: &'a PixelHistoryEntry) -> BoxFuture<'a, StoreResult<u64>>;
+
+    /// Sequence number of the latest placement, 0 if none
+    ///
+    /// Sequence numbers start at 1 and grow by one for each placement of every instance
+    fn history_seq(&self) -> BoxFuture<'_, StoreResult<u64>>;
+
+    /// Get history of a single pixel, oldest first
+    fn pixel_history(&self, pos_x: u16, pos_y: u16) -> BoxFuture<'_, StoreResult<Vec<PixelHistoryEntry>>>;
+
+    /// Get accepted placements with `since <= timestamp <= until`, in placement order
+    fn history_range(&self, since: u64, until: u64) -> BoxFuture<'_, StoreResult<Vec<PixelHistoryEntry>>>;
+
+    /// Save a whole canvas snapshot
+    fn snapshot_save<'a>(&'a self, snapshot: &'a CanvasSnapshot) -> BoxFuture<'a, StoreResult<()>>;
+
+    /// Get the latest snapshot taken at or before timestamp
+    fn snapshot_get(&self, timestamp: u64) -> BoxFuture<'_, StoreResult<Option<CanvasSnapshot>>>;
+
+    /// Spend one client pixel, write the pixel color bits and log it in history, as one atomic operation
+    fn pixel_place<'a>(&'a self, uuid: &'a str, pixel_update: &'a PixelColorUpdateMessage) -> BoxFuture<'a, StoreResult<Placement>>;
+
+    /// Layout chunks are stored with, the legacy one if chunks exist without a saved layout
+    fn layout_stored(&self) -> BoxFuture<'_, StoreResult<Option<CanvasLayout>>> {
+        Box::pin(async move {
+            if let Some(layout) = self.layout_get().await? {
+                return Ok(Some(layout));
+            }
+
+            let config = self.config();
+            let (chunk_rows, chunk_cols) = config.canvas_chunks();
+            for index_x in 0..chunk_rows {
+                for index_y in 0..chunk_cols {
+                    if self.chunk_exists(index_x, index_y).await? {
+                        return Ok(Some(CanvasLayout::legacy(&config)));
+                    }
+                }
+            }
+            Ok(None)
+        })
+    }
+
+    /// Get all chunks of the canvas, indexed by `[chunk_index_x][chunk_index_y]`
+    fn canvas_get(&self) -> BoxFuture<'_, StoreResult<CanvasChunks>> {
+        Box::pin(async move {
+            let (chunk_rows, chunk_cols) = self.config().canvas_chunks();
+
+            let mut result = Vec::with_capacity(chunk_rows);
+            for index_x in 0..chunk_rows {
+                let mut row_vec = Vec::with_capacity(chunk_cols);
+                for index_y in 0..chunk_cols {
+                    row_vec.push(self.chunk_get(index_x, index_y).await?);
+                }
+                result.push(row_vec);
+            }
+
+            Ok(result)
+        })
+    }
+
+    /// Replace every chunk of the canvas, indexed by `[chunk_index_x][chunk_index_y]`
+    fn canvas_set<'a>(&'a self, chunks: &'a CanvasChunks) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            for (index_x, chunk_row) in chunks.iter().enumerate() {
+                for (index_y, chunk) in chunk_row.iter().enumerate() {
+                    self.chunk_set(index_x, index_y, chunk).await?;
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Latest snapshot to replay history from up to timestamp, `None` to replay the whole history over an empty canvas
+    ///
+    /// Fails if the history before timestamp was placed with another layout
+    fn snapshot_base(&self, timestamp: u64) -> BoxFuture<'_, StoreResult<Option<CanvasSnapshot>>> {
+        Box::pin(async move {
+            let snapshot = self.snapshot_get(timestamp).await?;
+            match &snapshot {
+                Some(snapshot) => snapshot.layout_check(&self.config())?,
+                None => if let Some(migration) = self.migration_get().await? {
+                    migration.check(timestamp)?;
+                }
+            }
+
+            Ok(snapshot)
+        })
+    }
+
+    /// Rebuild the canvas as it was at timestamp, from latest snapshot and history replay
+    fn canvas_get_at(&self, timestamp: u64) -> BoxFuture<'_, StoreResult<CanvasChunks>> {
+        Box::pin(async move {
+            let config = &*self.config();
+            // entries of the snapshot second are replayed too, last placement of a pixel wins anyway
+            let (since, mut chunks) = match self.snapshot_base(timestamp).await? {
+                Some(snapshot) => (snapshot.timestamp, snapshot.chunks),
+                None => (0, CanvasChunk::canvas_empty(config))
+            };
+
+            for entry in self.history_range(since, timestamp).await? {
+                CanvasChunk::canvas_pixel_write(config, &mut chunks, entry.pos_x.into(), entry.pos_y.into(), entry.color);
+            }
+
+            Ok(chunks)
+        })
+    }
+
+    /// Save current canvas as a snapshot
+    fn snapshot_take(&self) -> BoxFuture<'_, StoreResult<CanvasSnapshot>> {
+        Box::pin(async move {
+            let snapshot = CanvasSnapshot::new(&self.config(), Client::timestamp_now(), self.canvas_get().await?);
+            self.snapshot_save(&snapshot).await?;
+
+            Ok(snapshot)
+        })
+    }
+}
+
+/// Chunk layout shared by every store implementation
+pub struct CanvasChunk;
+impl CanvasChunk {
+    pub fn chunk_index_to_key(chunk_index_x: usize, chunk_index_y: usize) -> String {
+        format!("{}_{}_{}", model::CANVAS_DB_KEY, chunk_index_x, chunk_index_y)
+    }
+
+    pub fn chunk_index_to_version_key(chunk_index_x: usize, chunk_index_y: usize) -> String {
+        format!("{}_version", Self::chunk_index_to_key(chunk_index_x, chunk_index_y))
+    }
+
+    /// Size in bytes of a whole chunk
+    pub fn chunk_byte_size(config: &model::Config) -> usize {
+        CanvasLayout::new(config).chunk_byte_size()
+    }
+
+    /// Bit offset of a pixel inside its chunk
+    pub fn pixel_bit_offset(config: &model::Config, chunk_pos: (usize, usize)) -> usize {
+        let (chunk_pos_x, chunk_pos_y) = chunk_pos;
+        (chunk_pos_y * config.canvas_chunk_size + chunk_pos_x) * config.bits_per_pixel()
+    }
+
+    /// Read the color bits of a pixel from chunk bytes
+    pub fn pixel_read(config: &model::Config, chunk: &[u8], chunk_pos: (usize, usize)) -> model::PixelColorUpdateMessageColor {
+        Self::bits_read(chunk, Self::pixel_bit_offset(config, chunk_pos), config.bits_per_pixel())
+    }
+
+    /// Color of every chunk pixel, row by row
+    pub fn chunk_colors(config: &model::Config, chunk: &[u8]) -> Vec<model::PixelColorUpdateMessageColor> {
+        let bits_per_pixel = config.bits_per_pixel();
+        (0..config.canvas_chunk_size * config.canvas_chunk_size)
+            .map(|pixel_index| Self::bits_read(chunk, pixel_index * bits_per_pixel, bits_per_pixel))
+            .collect()
+    }
+
+    /// Read a color of `bits_per_pixel` bits, most significant bit first
+    fn bits_read(chunk: &[u8], bit_offset: usize, bits_per_pixel: usize) -> model::PixelColorUpdateMessageColor {
+        let mut color = 0;
+        for i in 0..bits_per_pixel {
+            let offset = bit_offset + (bits_per_pixel - 1 - i);
+            let mask = 0b1000_0000 >> (offset % 8);
+
+            if chunk.get(offset / 8).is_some_and(|byte| byte & mask > 0) {
+                color |= 1 << i;
+            }
+        }
+        color
+    }
+
+    /// Empty canvas, all pixels with first color
+    pub fn canvas_empty(config: &model::Config) -> CanvasChunks {
+        let (chunk_rows, chunk_cols) = config.canvas_chunks();
+        vec![vec![vec![0; Self::chunk_byte_size(config)]; chunk_cols]; chunk_rows]
+    }
+
+    /// Write the color bits of a pixel into whole canvas chunks
+    pub fn canvas_pixel_write(config: &model::Config, chunks: &mut CanvasChunks, pos_x: usize, pos_y: usize, color: model::PixelColorUpdateMessageColor) {
+        let ((chunk_index_x, chunk_index_y), chunk_pos) = config.canvas_pos_to_chunk_location(pos_x, pos_y);
+        if let Some(chunk) = chunks.get_mut(chunk_index_x).and_then(|row| row.get_mut(chunk_index_y)) {
+            Self::pixel_write(config, chunk, chunk_pos, color);
+        }
+    }
+
+    /// Write the color bits of a pixel into chunk bytes, same bit order as redis `SETBIT`
+    ///
+    /// Like `SETBIT`, a chunk shorter than the layout is padded with zeros first.
+    pub fn pixel_write(config: &model::Config, chunk: &mut Vec<u8>, chunk_pos: (usize, usize), color: model::PixelColorUpdateMessageColor) {
+        let bit_offset = Self::pixel_bit_offset(config, chunk_pos);
+        let bits_per_pixel = config.bits_per_pixel();
+        chunk.resize(chunk.len().max(Self::chunk_byte_size(config)), 0);
+
+        for i in 0..bits_per_pixel {
+            let is_bit_one = (color & (1 << i)) > 0;
+
+            let offset = bit_offset + (bits_per_pixel - 1 - i);
+            let mask = 0b1000_0000 >> (offset % 8);
+
+            if is_bit_one {
+                chunk[offset / 8] |= mask;
+            } else {
+                chunk[offset / 8] &= !mask;
+            }
+        }
+    }
+}
+
+/// New random chunk epoch
+fn epoch_new() -> String {
+    let (epoch, _) = Uuid::new_v4().as_u64_pair();
+    format!("{epoch:016x}")
+}
+
+/// Create the store selected by `Config::storage`, refusing chunks written with another layout
+pub async fn open_store(config: &SharedConfig) -> Result<Arc<dyn CanvasStore>, Box<dyn Error>> {
+    let store = open_store_unchecked(config).await?;
+
+    let layout = CanvasLayout::new(&config.get());
+    match store.layout_stored().await? {
+        Some(stored_layout) if stored_layout != layout => {
+            return Err(format!("Canvas is stored with {stored_layout} but config needs {layout}, run `migrate --from <previous config>` first").into());
+        },
+        // also saves the legacy layout when it matches
+        _ => store.layout_set(&layout).await?,
+    }
+
+    Ok(store)
+}
+
+/// Create the store without checking its layout, for `migrate` which writes chunks again
+pub async fn open_store_unchecked(config: &SharedConfig) -> Result<Arc<dyn CanvasStore>, Box<dyn Error>> {
+    let current_config = config.get();
+    let store: Arc<dyn CanvasStore> = match current_config.storage {
+        StorageKind::Redis => {
+            log::info!("Starting redis on {}", &current_config.redis_url);
+            let redis_client = redis::Client::open(current_config.redis_url.clone())?;
+            Arc::new(RedisStore::new(redis_client, config.clone()).await?)
+        },
+        StorageKind::Memory => {
+            log::info!("Using in-memory storage, canvas will not be persisted");
+            Arc::new(MemoryStore::new(config.clone()))
+        },
+        StorageKind::Embedded => {
+            log::info!("Opening embedded storage at {}", current_config.embedded_path.display());
+            Arc::new(EmbeddedStore::open(&current_config.embedded_path, config.clone())?)
+        },
+    };
+
+    Ok(store)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pixel_write_read_round_trip() {
+        for (color_count, bits_per_pixel) in [(2, 1), (4, 2), (16, 4), (256, 8)] {
+            let config = model::Config::test(color_count);
+            assert_eq!(config.bits_per_pixel(), bits_per_pixel);
+
+            let mut chunk = vec![0; CanvasChunk::chunk_byte_size(&config)];
+            let color_at = |pos_x: usize, pos_y: usize| ((pos_x * 7 + pos_y * 3 + 1) % color_count) as u8;
+            for pos_y in 0..3 {
+                for pos_x in 0..3 {
+                    CanvasChunk::pixel_write(&config, &mut chunk, (pos_x, pos_y), color_at(pos_x, pos_y));
+                }
+            }
+
+            // overwrite one pixel, neighbours sharing its byte must keep their bits
+            let last_color = (color_count - 1) as u8;
+            CanvasChunk::pixel_write(&config, &mut chunk, (1, 1), last_color);
+            let colors = CanvasChunk::chunk_colors(&config, &chunk);
+            for pos_y in 0..3 {
+                for pos_x in 0..3 {
+                    let expected = if (pos_x, pos_y) == (1, 1) { last_color } else { color_at(pos_x, pos_y) };
+                    assert_eq!(CanvasChunk::pixel_read(&config, &chunk, (pos_x, pos_y)), expected, "{bits_per_pixel} bits at {pos_x},{pos_y}");
+                    assert_eq!(colors[pos_y * 3 + pos_x], expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pixel_write_matches_setbit_order() {
+        let config = model::Config::test(4);
+        let mut chunk = vec![0; CanvasChunk::chunk_byte_size(&config)];
+        CanvasChunk::pixel_write(&config, &mut chunk, (1, 0), 0b01);
+        assert_eq!(chunk[0], 0b0001_0000);
+        assert_eq!(CanvasChunk::bits_read(&chunk, 2, 2), 0b01);
+    }
+
+    #[test]
+    fn pixel_write_pads_short_chunk() {
+        let config = model::Config::test(16);
+        let mut chunk = vec![0b1010_0000];
+        CanvasChunk::pixel_write(&config, &mut chunk, (2, 2), 5);
+
+        assert_eq!(chunk.len(), CanvasChunk::chunk_byte_size(&config));
+        assert_eq!(CanvasChunk::pixel_read(&config, &chunk, (0, 0)), 0b1010);
+        assert_eq!(CanvasChunk::pixel_read(&config, &chunk, (2, 2)), 5);
+    }
+
+    #[tokio::test]
+    async fn layout_stored_legacy_without_saved_layout() {
+        let config = model::Config::test(4);
+        let store = memory_store::MemoryStore::new(config.clone().into());
+        assert_eq!(store.layout_stored().await.unwrap(), None);
+
+        // chunks written before layouts were saved always have 4 bits
+        store.chunk_set(2, 1, &[0; 3]).await.unwrap();
+        assert_eq!(store.layout_stored().await.unwrap(), Some(CanvasLayout { bits_per_pixel: 4, chunk_size: 3 }));
+
+        store.layout_set(&CanvasLayout::new(&config)).await.unwrap();
+        assert_eq!(store.layout_stored().await.unwrap(), Some(CanvasLayout { bits_per_pixel: 2, chunk_size: 3 }));
+    }
+
+    #[test]
+    fn snapshot_bytes_round_trip() {
+        let config = model::Config::test(4);
+        let mut chunks = CanvasChunk::canvas_empty(&config);
+        CanvasChunk::canvas_pixel_write(&config, &mut chunks, 7, 2, 3);
+        let snapshot = CanvasSnapshot::new(&config, 100, chunks);
+
+        let read = CanvasSnapshot::from_bytes(100, &snapshot.to_bytes()).unwrap();
+        assert_eq!((read.layout, &read.chunks), (snapshot.layout, &snapshot.chunks));
+        read.layout_check(&config).unwrap();
+
+        // snapshots saved before layouts were concatenated chunks only
+        let legacy_bytes: Vec<u8> = snapshot.chunks.iter().flatten().flatten().copied().collect();
+        assert!(CanvasSnapshot::from_bytes(100, &legacy_bytes).is_err());
+    }
+
+    #[test]
+    fn snapshot_of_another_layout_refused() {
+        let config = model::Config::test(16);
+        let snapshot = CanvasSnapshot::new(&config, 100, CanvasChunk::canvas_empty(&config));
+
+        let migrated = model::Config::test(4);
+        assert!(snapshot.layout_check(&migrated).is_err());
+        let resized = model::Config { canvas_width: 12, ..model::Config::test(16) };
+        assert!(snapshot.layout_check(&resized).is_err());
+    }
+
+    #[test]
+    fn bits_read_past_chunk_end_is_zero() {
+        assert_eq!(CanvasChunk::bits_read(&[0xff], 8, 8), 0);
+        assert_eq!(CanvasChunk::bits_read(&[0xff], 4, 8), 0b1111_0000);
+    }
+}
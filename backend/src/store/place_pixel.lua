@@ -0,0 +1,93 @@
+-- Atomically spend one client pixel and write its color bits
+--
+-- KEYS[1] client uuid
+-- KEYS[2] chunk key
+-- KEYS[3] history stream key
+-- KEYS[4] pixel history list key
+-- KEYS[5] chunk version key
+-- KEYS[6] history sequence number key
+-- ARGV[1] current timestamp in seconds
+-- ARGV[2] timeout in seconds
+-- ARGV[3] base pixel amount
+-- ARGV[4] chunk size in bytes
+-- ARGV[5] pixel bit offset inside chunk
+-- ARGV[6] pixel color
+-- ARGV[7] pixel x position
+-- ARGV[8] pixel y position
+-- ARGV[9] quota mode, refillAll or tokenBucket
+-- ARGV[10] bits per pixel
+--
+-- returns { placed (0 or 1), client json, placement sequence number (0 if not placed) }
+
+local now = tonumber(ARGV[1])
+local timeout = tonumber(ARGV[2])
+local base_pixel_amount = tonumber(ARGV[3])
+local chunk_byte_size = tonumber(ARGV[4])
+local bit_offset = tonumber(ARGV[5])
+local color = tonumber(ARGV[6])
+local pos_x = tonumber(ARGV[7])
+local pos_y = tonumber(ARGV[8])
+local quota_mode = ARGV[9]
+local bits_per_pixel = tonumber(ARGV[10])
+
+local client
+local client_json = redis.call('GET', KEYS[1])
+if client_json then
+    client = cjson.decode(client_json)
+else
+    client = { lastTimestamp = now, remainingPixels = base_pixel_amount }
+end
+
+-- refill, same rules as Client::refill
+if quota_mode == 'tokenBucket' then
+    if client.remainingPixels >= base_pixel_amount then
+        client.lastTimestamp = now
+    elseif timeout == 0 then
+        client.remainingPixels = base_pixel_amount
+        client.lastTimestamp = now
+    else
+        local regenerated = math.floor(math.max(now - client.lastTimestamp, 0) * base_pixel_amount / timeout)
+        if regenerated > 0 then
+            client.remainingPixels = client.remainingPixels + regenerated
+            if client.remainingPixels >= base_pixel_amount then
+                client.remainingPixels = base_pixel_amount
+                client.lastTimestamp = now
+            else
+                client.lastTimestamp = client.lastTimestamp + math.ceil(regenerated * timeout / base_pixel_amount)
+            end
+        end
+    end
+-- agree with 1s margin
+elseif client.remainingPixels == 0 and now - client.lastTimestamp >= timeout - 1 then
+    client.remainingPixels = base_pixel_amount
+    client.lastTimestamp = now
+end
+
+if client.remainingPixels == 0 then
+    return { 0, cjson.encode(client), 0 }
+end
+
+-- counters first, an INCR failing on a corrupted key must abort before anything is written
+redis.call('INCR', KEYS[5])
+local seq = redis.call('INCR', KEYS[6])
+
+client.remainingPixels = client.remainingPixels - 1
+client_json = cjson.encode(client)
+redis.call('SET', KEYS[1], client_json)
+
+-- create full size chunk if missing
+if redis.call('EXISTS', KEYS[2]) == 0 then
+    redis.call('SETBIT', KEYS[2], chunk_byte_size * 8 - 1, 0)
+end
+
+for i = 0, bits_per_pixel - 1 do
+    local bit = math.floor(color / (2 ^ i)) % 2
+    redis.call('SETBIT', KEYS[2], bit_offset + (bits_per_pixel - 1 - i), bit)
+end
+
+-- log placement in global and pixel history
+local entry_json = cjson.encode({ posX = pos_x, posY = pos_y, color = color, uuid = KEYS[1], timestamp = now })
+redis.call('XADD', KEYS[3], '*', 'entry', entry_json)
+redis.call('RPUSH', KEYS[4], entry_json)
+
+return { 1, client_json, seq }
@@ -0,0 +1,352 @@
+use std::sync::Arc;
+
+use futures_util::future::BoxFuture;
+use redis::{aio::ConnectionManager, streams::StreamRangeReply, AsyncCommands, RedisError, RedisResult, Script};
+
+use crate::model::{self, Client, PixelColorUpdateMessage, PixelHistoryEntry, SharedConfig};
+
+use super::{epoch_new, CanvasChunk, CanvasLayout, CanvasMigration, CanvasSnapshot, CanvasStore, ChunkVersions, Placement, StoreResult};
+
+/// History stream ids come from redis clock, entry timestamps from instances clocks
+const HISTORY_CLOCK_MARGIN_SECS: u64 = 60;
+
+fn pixel_history_key(pos_x: u16, pos_y: u16) -> String {
+    format!("{}_{}_{}", model::HISTORY_DB_KEY, pos_x, pos_y)
+}
+
+fn snapshot_key(timestamp: u64) -> String {
+    format!("{}_{}", model::SNAPSHOT_DB_KEY, timestamp)
+}
+
+pub struct RedisStore {
+    config: SharedConfig,
+    /// Shared multiplexed connection, reconnects by itself
+    con: ConnectionManager,
+    place_pixel_script: Script,
+}
+
+impl RedisStore {
+    pub async fn new(redis_client: redis::Client, config: SharedConfig) -> RedisResult<Self> {
+        let con = ConnectionManager::new(redis_client).await?;
+
+        Ok(Self {
+            config,
+            con,
+            place_pixel_script: Script::new(include_str!("place_pixel.lua")),
+        })
+    }
+
+    async fn chunk_create(&self, con: &mut impl AsyncCommands, chunk_key: &str) -> Result<Vec<u8>, RedisError> {
+        let vec_size = CanvasChunk::chunk_byte_size(&self.config());
+        con.setbit::<_, ()>(chunk_key, vec_size * 8 - 1, false).await?; // set latest chunk bit (thus *8 - 1) to create empty string with 0 value
+        Ok(vec![0; vec_size])
+    }
+}
+
+impl CanvasStore for RedisStore {
+    fn config(&self) -> Arc<model::Config> {
+        self.config.get()
+    }
+
+    fn layout_get(&self) -> BoxFuture<'_, StoreResult<Option<CanvasLayout>>> {
+        Box::pin(async move {
+            let mut con = self.con.clone();
+
+            let opt_layout_string: Option<String> = con.get(model::LAYOUT_DB_KEY).await?;
+            match opt_layout_string {
+                Some(layout_string) => Ok(Some(serde_json::from_str(&layout_string)?)),
+                None => Ok(None)
+            }
+        })
+    }
+
+    fn layout_set<'a>(&'a self, layout: &'a CanvasLayout) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            let mut con = self.con.clone();
+            con.set::<_, _, ()>(model::LAYOUT_DB_KEY, serde_json::to_string(layout)?).await?;
+            Ok(())
+        })
+    }
+
+    fn migration_get(&self) -> BoxFuture<'_, StoreResult<Option<CanvasMigration>>> {
+        Box::pin(async move {
+            let mut con = self.con.clone();
+
+            let opt_migration_string: Option<String> = con.get(model::MIGRATION_DB_KEY).await?;
+            match opt_migration_string {
+                Some(migration_string) => Ok(Some(serde_json::from_str(&migration_string)?)),
+                None => Ok(None)
+            }
+        })
+    }
+
+    fn migration_set<'a>(&'a self, migration: &'a CanvasMigration) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            let mut con = self.con.clone();
+            con.set::<_, _, ()>(model::MIGRATION_DB_KEY, serde_json::to_string(migration)?).await?;
+            Ok(())
+        })
+    }
+
+    fn chunk_epoch(&self) -> BoxFuture<'_, StoreResult<String>> {
+        Box::pin(async move {
+            let mut con = self.con.clone();
+
+            // read every time, a flushed database gets a new epoch
+            if let Some(epoch) = con.get(model::EPOCH_DB_KEY).await? {
+                return Ok(epoch);
+            }
+            con.set_nx::<_, _, ()>(model::EPOCH_DB_KEY, epoch_new()).await?;
+            Ok(con.get(model::EPOCH_DB_KEY).await?)
+        })
+    }
+
+    fn chunk_get(&self, chunk_index_x: usize, chunk_index_y: usize) -> BoxFuture<'_, StoreResult<Vec<u8>>> {
+        Box::pin(async move {
+            let mut con = self.con.clone();
+
+            let chunk_key = CanvasChunk::chunk_index_to_key(chunk_index_x, chunk_index_y);
+            let opt_colors: Option<Vec<u8>> = con.get(&chunk_key).await?;
+
+            let mut colors = match opt_colors {
+                Some(colors) => colors,
+                None => self.chunk_create(&mut con, &chunk_key).await?
+            };
+
+            colors.truncate(CanvasChunk::chunk_byte_size(&self.config()));
+            Ok(colors)
+        })
+    }
+
+    fn chunk_exists(&self, chunk_index_x: usize, chunk_index_y: usize) -> BoxFuture<'_, StoreResult<bool>> {
+        Box::pin(async move {
+            let mut con = self.con.clone();
+            Ok(con.exists(CanvasChunk::chunk_index_to_key(chunk_index_x, chunk_index_y)).await?)
+        })
+    }
+
+    fn chunk_version(&self, chunk_index_x: usize, chunk_index_y: usize) -> BoxFuture<'_, StoreResult<(String, u64)>> {
+        Box::pin(async move {
+            let mut con = self.con.clone();
+
+            let (opt_epoch, opt_version): (Option<String>, Option<u64>) = redis::pipe()
+                .get(model::EPOCH_DB_KEY)
+                .get(CanvasChunk::chunk_index_to_version_key(chunk_index_x, chunk_index_y))
+                .query_async(&mut con).await?;
+
+            // only a new or flushed database has no epoch yet
+            let epoch = match opt_epoch {
+                Some(epoch) => epoch,
+                None => self.chunk_epoch().await?,
+            };
+            Ok((epoch, opt_version.unwrap_or(0)))
+        })
+    }
+
+    fn chunk_versions(&self) -> BoxFuture<'_, StoreResult<(String, ChunkVersions)>> {
+        Box::pin(async move {
+            let mut con = self.con.clone();
+            let (chunk_rows, chunk_cols) = self.config().canvas_chunks();
+
+            let version_keys: Vec<_> = (0..chunk_rows)
+                .flat_map(|index_x| (0..chunk_cols).map(move |index_y| CanvasChunk::chunk_index_to_version_key(index_x, index_y)))
+                .collect();
+            let (opt_epoch, opt_versions): (Option<String>, Vec<Option<u64>>) = redis::pipe()
+                .get(model::EPOCH_DB_KEY)
+                .get(version_keys)
+                .query_async(&mut con).await?;
+
+            // only a new or flushed database has no epoch yet
+            let epoch = match opt_epoch {
+                Some(epoch) => epoch,
+                None => self.chunk_epoch().await?,
+            };
+            let versions = opt_versions.chunks(chunk_cols)
+                .map(|versions| versions.iter().map(|version| version.unwrap_or(0)).collect())
+                .collect();
+            Ok((epoch, versions))
+        })
+    }
+
+    fn chunk_set<'a>(&'a self, chunk_index_x: usize, chunk_index_y: usize, chunk: &'a [u8]) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            let mut con = self.con.clone();
+
+            redis::pipe()
+                .atomic()
+                .set(CanvasChunk::chunk_index_to_key(chunk_index_x, chunk_index_y), chunk).ignore()
+                .incr(CanvasChunk::chunk_index_to_version_key(chunk_index_x, chunk_index_y), 1).ignore()
+                .query_async::<()>(&mut con).await?;
+
+            Ok(())
+        })
+    }
+
+    fn pixel_set<'a>(&'a self, pixel_update: &'a PixelColorUpdateMessage) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            let mut con = self.con.clone();
+
+            let (chunk_index, chunk_pos) = self.config().canvas_pos_to_chunk_location(pixel_update.pos_x.into(), pixel_update.pos_y.into());
+            let (chunk_index_x, chunk_index_y) = chunk_index;
+
+            let chunk_key = CanvasChunk::chunk_index_to_key(chunk_index_x, chunk_index_y);
+            let bit_offset = CanvasChunk::pixel_bit_offset(&self.config(), chunk_pos);
+            let bits_per_pixel = self.config().bits_per_pixel();
+
+            // all color bits and version in one transaction
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            pipe.incr(CanvasChunk::chunk_index_to_version_key(chunk_index_x, chunk_index_y), 1).ignore();
+            for i in 0..bits_per_pixel {
+                let is_bit_one = (pixel_update.color & (1 << i)) > 0;
+
+                let redis_offset = bit_offset + (bits_per_pixel - 1 - i);
+
+                pipe.setbit(&chunk_key, redis_offset, is_bit_one).ignore();
+            }
+            pipe.query_async::<()>(&mut con).await?;
+
+            Ok(())
+        })
+    }
+
+    fn pixel_place<'a>(&'a self, uuid: &'a str, pixel_update: &'a PixelColorUpdateMessage) -> BoxFuture<'a, StoreResult<Placement>> {
+        Box::pin(async move {
+            let mut con = self.con.clone();
+
+            let (chunk_index, chunk_pos) = self.config().canvas_pos_to_chunk_location(pixel_update.pos_x.into(), pixel_update.pos_y.into());
+            let (chunk_index_x, chunk_index_y) = chunk_index;
+
+            let (placed, client_string, seq): (bool, String, u64) = self.place_pixel_script
+                .key(uuid)
+                .key(CanvasChunk::chunk_index_to_key(chunk_index_x, chunk_index_y))
+                .key(model::HISTORY_DB_KEY)
+                .key(pixel_history_key(pixel_update.pos_x, pixel_update.pos_y))
+                .key(CanvasChunk::chunk_index_to_version_key(chunk_index_x, chunk_index_y))
+                .key(model::HISTORY_SEQ_DB_KEY)
+                .arg(Client::timestamp_now())
+                .arg(self.config().timeout.as_secs())
+                .arg(self.config().base_pixel_amount)
+                .arg(CanvasChunk::chunk_byte_size(&self.config()))
+                .arg(CanvasChunk::pixel_bit_offset(&self.config(), chunk_pos))
+                .arg(pixel_update.color)
+                .arg(pixel_update.pos_x)
+                .arg(pixel_update.pos_y)
+                .arg(self.config().quota_mode.as_str())
+                .arg(self.config().bits_per_pixel())
+                .invoke_async(&mut con).await?;
+
+            let client = Client::decode_json(client_string)?;
+            Ok(if placed { Placement::Placed(client, seq) } else { Placement::NoPixelsLeft(client) })
+        })
+    }
+
+    fn history_append<'a>(&'a self, entry: &'a PixelHistoryEntry) -> BoxFuture<'a, StoreResult<u64>> {
+        Box::pin(async move {
+            let mut con = self.con.clone();
+
+            let entry_json = entry.encode_json()?;
+            let (seq,): (u64,) = redis::pipe()
+                .atomic()
+                .incr(model::HISTORY_SEQ_DB_KEY, 1)
+                .xadd(model::HISTORY_DB_KEY, "*", &[("entry", &entry_json)]).ignore()
+                .rpush(pixel_history_key(entry.pos_x, entry.pos_y), &entry_json).ignore()
+                .query_async(&mut con).await?;
+
+            Ok(seq)
+        })
+    }
+
+    fn history_seq(&self) -> BoxFuture<'_, StoreResult<u64>> {
+        Box::pin(async move {
+            let mut con = self.con.clone();
+
+            let opt_seq: Option<u64> = con.get(model::HISTORY_SEQ_DB_KEY).await?;
+            Ok(opt_seq.unwrap_or(0))
+        })
+    }
+
+    fn pixel_history(&self, pos_x: u16, pos_y: u16) -> BoxFuture<'_, StoreResult<Vec<PixelHistoryEntry>>> {
+        Box::pin(async move {
+            let mut con = self.con.clone();
+
+            let entries: Vec<String> = con.lrange(pixel_history_key(pos_x, pos_y), 0, -1).await?;
+            Ok(entries.into_iter()
+                .map(PixelHistoryEntry::decode_json)
+                .collect::<Result<Vec<_>, _>>()?)
+        })
+    }
+
+    fn history_range(&self, since: u64, until: u64) -> BoxFuture<'_, StoreResult<Vec<PixelHistoryEntry>>> {
+        Box::pin(async move {
+            let mut con = self.con.clone();
+
+            // stream ids are milliseconds timestamps
+            let start_id = since.saturating_sub(HISTORY_CLOCK_MARGIN_SECS) * 1000;
+            let end_id = until.saturating_add(HISTORY_CLOCK_MARGIN_SECS).saturating_mul(1000);
+            let reply: StreamRangeReply = con.xrange(model::HISTORY_DB_KEY, start_id, end_id).await?;
+
+            let mut entries = Vec::with_capacity(reply.ids.len());
+            for stream_id in reply.ids {
+                let Some(entry_json) = stream_id.get::<String>("entry") else { continue };
+                let entry = PixelHistoryEntry::decode_json(entry_json)?;
+                if entry.timestamp >= since && entry.timestamp <= until {
+                    entries.push(entry);
+                }
+            }
+
+            Ok(entries)
+        })
+    }
+
+    fn snapshot_save<'a>(&'a self, snapshot: &'a CanvasSnapshot) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            let mut con = self.con.clone();
+
+            redis::pipe()
+                .atomic()
+                .set(snapshot_key(snapshot.timestamp), snapshot.to_bytes()).ignore()
+                .zadd(model::SNAPSHOT_DB_KEY, snapshot.timestamp, snapshot.timestamp).ignore()
+                .query_async::<()>(&mut con).await?;
+
+            Ok(())
+        })
+    }
+
+    fn snapshot_get(&self, timestamp: u64) -> BoxFuture<'_, StoreResult<Option<CanvasSnapshot>>> {
+        Box::pin(async move {
+            let mut con = self.con.clone();
+
+            let snapshot_timestamps: Vec<u64> = con.zrevrangebyscore_limit(model::SNAPSHOT_DB_KEY, timestamp, "-inf", 0, 1).await?;
+            let Some(snapshot_timestamp) = snapshot_timestamps.first().copied() else {
+                return Ok(None);
+            };
+
+            let opt_bytes: Option<Vec<u8>> = con.get(snapshot_key(snapshot_timestamp)).await?;
+            opt_bytes.map(|bytes| CanvasSnapshot::from_bytes(snapshot_timestamp, &bytes)).transpose()
+        })
+    }
+
+    fn client_get<'a>(&'a self, uuid: &'a str) -> BoxFuture<'a, StoreResult<Option<Client>>> {
+        Box::pin(async move {
+            let mut con = self.con.clone();
+
+            let opt_client_string: Option<String> = con.get(uuid).await?;
+            match opt_client_string {
+                Some(client_string) => Ok(Some(Client::decode_json(client_string)?)),
+                None => Ok(None)
+            }
+        })
+    }
+
+    fn client_set<'a>(&'a self, uuid: &'a str, client: &'a Client) -> BoxFuture<'a, StoreResult<()>> {
+        Box::pin(async move {
+            let mut con = self.con.clone();
+
+            let client_string = client.encode_json()?;
+            con.set::<_, _, ()>(uuid, client_string).await?;
+
+            Ok(())
+        })
+    }
+}
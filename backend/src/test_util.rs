@@ -0,0 +1,24 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use uuid::Uuid;
+
+/// Unique path in the temporary directory, the file or directory written there is removed when dropped
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    pub fn new(name: &str) -> Self {
+        Self(env::temp_dir().join(format!("place-{}-{name}", Uuid::new_v4())))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = if self.0.is_dir() { fs::remove_dir_all(&self.0) } else { fs::remove_file(&self.0) };
+    }
+}
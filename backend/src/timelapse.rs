@@ -0,0 +1,206 @@
+use std::borrow::Cow;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::model::Client;
+use crate::render::{self, Region};
+use crate::store::CanvasStore;
+
+/// Frames of a single timelapse, larger histories need a longer interval
+pub const MAX_TIMELAPSE_FRAMES: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimelapseFormat {
+    Gif,
+    Apng,
+    /// Directory of PNG frames
+    Frames,
+}
+
+impl FromStr for TimelapseFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gif" => Ok(Self::Gif),
+            "apng" => Ok(Self::Apng),
+            "frames" => Ok(Self::Frames),
+            _ => Err(format!("Unknown timelapse format {s}, expected gif, apng or frames"))
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TimelapseOptions {
+    pub format: TimelapseFormat,
+    /// Output file, or directory for frames
+    pub output: PathBuf,
+    /// Canvas time between two frames, in seconds
+    pub interval: u64,
+    /// Display duration of a frame, in milliseconds
+    pub frame_delay: u16,
+    /// Canvas part to render, whole canvas if `None`
+    pub region: Option<Region>,
+    pub scale: usize,
+}
+
+enum FrameWriter {
+    Gif(gif::Encoder<BufWriter<File>>),
+    Apng(png::Writer<BufWriter<File>>),
+    Frames(PathBuf),
+}
+
+/// Replay placement history into an animation, returns the number of frames written
+pub async fn timelapse(store: &dyn CanvasStore, options: &TimelapseOptions) -> Result<usize, Box<dyn Error>> {
+    let config = &*store.config();
+    if options.interval == 0 || options.scale == 0 {
+        return Err("Timelapse interval and scale must be greater than 0".into());
+    }
+
+    let region = options.region.unwrap_or(Region::full(config))
+        .clamp(config)
+        .ok_or("Timelapse region is outside of the canvas")?;
+    let width = region.width * options.scale;
+    let height = region.height * options.scale;
+    let palette = render::palette_rgb(config);
+
+    let entries = store.history_range(0, Client::timestamp_now()).await?;
+    // placement order, timestamps may go back when server clocks disagree
+    let timestamps = entries.iter().map(|entry| entry.timestamp);
+    let (Some(start), Some(end)) = (timestamps.clone().min(), timestamps.max()) else {
+        return Err("No placement in history".into());
+    };
+    let frame_count = (end - start).div_ceil(options.interval).saturating_add(1);
+    if frame_count > MAX_TIMELAPSE_FRAMES as u64 {
+        return Err(format!(
+            "Timelapse would have {frame_count} frames, more than {MAX_TIMELAPSE_FRAMES}, use a longer interval"
+        ).into());
+    }
+    let frame_count = frame_count as usize;
+
+    // history may start after an import, replay over the canvas saved at that time
+    let mut indices = match store.snapshot_base(start).await? {
+        Some(snapshot) => render::canvas_color_indices(config, &snapshot.chunks),
+        None => vec![0; config.canvas_width * config.canvas_height],
+    };
+
+    let mut writer = match options.format {
+        TimelapseFormat::Gif => {
+            let file = BufWriter::new(File::create(&options.output)?);
+            let mut encoder = gif::Encoder::new(file, u16::try_from(width)?, u16::try_from(height)?, &palette)?;
+            encoder.set_repeat(gif::Repeat::Infinite)?;
+            FrameWriter::Gif(encoder)
+        },
+        TimelapseFormat::Apng => {
+            let file = BufWriter::new(File::create(&options.output)?);
+            let mut encoder = png::Encoder::new(file, width as u32, height as u32);
+            encoder.set_color(png::ColorType::Indexed);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_palette(palette.as_slice());
+            encoder.set_animated(frame_count as u32, 0)?;
+            encoder.set_frame_delay(options.frame_delay, 1000)?;
+            FrameWriter::Apng(encoder.write_header()?)
+        },
+        TimelapseFormat::Frames => {
+            fs::create_dir_all(&options.output)?;
+            FrameWriter::Frames(options.output.clone())
+        },
+    };
+
+    let mut entries = entries.iter().peekable();
+    for frame_index in 0..frame_count {
+        // apply every placement up to this frame
+        let frame_timestamp = start + frame_index as u64 * options.interval;
+        while let Some(entry) = entries.next_if(|entry| entry.timestamp <= frame_timestamp) {
+            let (pos_x, pos_y) = (entry.pos_x as usize, entry.pos_y as usize);
+            if pos_x < config.canvas_width && pos_y < config.canvas_height {
+                indices[pos_y * config.canvas_width + pos_x] = entry.color;
+            }
+        }
+
+        let frame = render::region_scaled(&indices, config.canvas_width, region, options.scale);
+        match &mut writer {
+            FrameWriter::Gif(encoder) => {
+                encoder.write_frame(&gif::Frame {
+                    width: width as u16,
+                    height: height as u16,
+                    delay: options.frame_delay / 10, // in 10ms units
+                    buffer: Cow::Borrowed(&frame),
+                    ..Default::default()
+                })?;
+            },
+            FrameWriter::Apng(png_writer) => png_writer.write_image_data(&frame)?,
+            FrameWriter::Frames(directory) => {
+                let file = BufWriter::new(File::create(directory.join(format!("frame_{:05}.png", frame_index)))?);
+                render::write_png(file, width, height, &palette, &frame)?;
+            },
+        }
+    }
+
+    if let FrameWriter::Apng(png_writer) = writer {
+        png_writer.finish()?;
+    }
+
+    Ok(frame_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{self, PixelColorUpdateMessage, PixelHistoryEntry};
+    use crate::store::{CanvasChunk, CanvasSnapshot, MemoryStore};
+    use crate::test_util::TempPath;
+
+    fn history_entry(pos_x: u16, pos_y: u16, color: u8, timestamp: u64) -> PixelHistoryEntry {
+        PixelHistoryEntry::new("author", &PixelColorUpdateMessage { pos_x, pos_y, color }, timestamp)
+    }
+
+    #[tokio::test]
+    async fn frames_over_snapshot_with_timestamps_going_back() {
+        let config = model::Config::test(4);
+        let store = MemoryStore::new(config.clone().into());
+        let mut chunks = CanvasChunk::canvas_empty(&config);
+        CanvasChunk::canvas_pixel_write(&config, &mut chunks, 1, 1, 2);
+        store.snapshot_save(&CanvasSnapshot::new(&config, 50, chunks)).await.unwrap();
+        store.history_append(&history_entry(0, 0, 1, 200)).await.unwrap();
+        store.history_append(&history_entry(2, 2, 3, 100)).await.unwrap();
+
+        let output = TempPath::new("timelapse-test");
+        let options = TimelapseOptions {
+            format: TimelapseFormat::Frames,
+            output: output.path().to_path_buf(),
+            interval: 50,
+            frame_delay: 100,
+            region: None,
+            scale: 1,
+        };
+        assert_eq!(timelapse(&store, &options).await.unwrap(), 3);
+
+        let mut reader = png::Decoder::new(File::open(output.path().join("frame_00000.png")).unwrap()).read_info().unwrap();
+        let mut frame = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut frame).unwrap();
+        assert_eq!(frame[config.canvas_width + 1], 2);
+    }
+
+    #[tokio::test]
+    async fn too_many_frames_refused() {
+        let config = model::Config::test(4);
+        let store = MemoryStore::new(config.into());
+        store.history_append(&history_entry(0, 0, 1, 0)).await.unwrap();
+        store.history_append(&history_entry(0, 0, 2, MAX_TIMELAPSE_FRAMES as u64)).await.unwrap();
+
+        let output = TempPath::new("timelapse-test");
+        let options = TimelapseOptions {
+            format: TimelapseFormat::Frames,
+            output: output.path().to_path_buf(),
+            interval: 1,
+            frame_delay: 100,
+            region: None,
+            scale: 1,
+        };
+        assert!(timelapse(&store, &options).await.is_err());
+    }
+}
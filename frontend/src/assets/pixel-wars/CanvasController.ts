@@ -7,6 +7,9 @@ const DEFAULT_SIZE = 252;
 const LEFT_BUTTON = 0;
 const MIDDLE_BUTTON = 1;
 
+// wait for the view to settle before sending chunks in view
+const VIEW_CHUNKS_DELAY = 200;
+
 function numberClamp(num: number, min: number, max: number) {
   return Math.min(Math.max(num, min), max)
 }
@@ -22,6 +25,8 @@ export class CanvasElementController {
   private canvas_move_frame_asked = false;
   private canvas_update_frame_asked = false;
 
+  private view_chunks_timeout: number | undefined = undefined;
+
   private mobile_is_zooming = false;
   private mobile_pinch_length_last = 0;
   private mobile_pan_position_last = [0,0];
@@ -35,6 +40,9 @@ export class CanvasElementController {
     this.updateCanvas();
 
     this.registerEventListeners();
+
+    // zoom, pan and canvas size all go through the store
+    CanvasInfoStore.subscribe(() => this.askViewChunks());
   }
 
   public setSize(width = DEFAULT_SIZE, height: number | undefined = undefined) {
@@ -65,6 +73,8 @@ export class CanvasElementController {
       this.canvas_can_move = false;
     });
 
+    window.addEventListener("resize", () => this.askViewChunks());
+
     window.addEventListener("wheel", (e) => {
       CanvasInfoStore.update((v) => ({ ...v, canvas_zoom: numberClamp(v.canvas_zoom + (e.deltaY < 0 ? 0.2 : -0.2), 0.3, 8) }));
     });
@@ -192,6 +202,45 @@ export class CanvasElementController {
     window.dispatchEvent(new CustomEvent("pixelClicked", { detail: { x, y } }));
   }
 
+  private askViewChunks() {
+    clearTimeout(this.view_chunks_timeout);
+    this.view_chunks_timeout = setTimeout(() => {
+      const chunks = this.getViewChunks();
+      if (chunks === undefined) return;
+      window.dispatchEvent(new CustomEvent("viewChunksChanged", { detail: { chunks } }));
+    }, VIEW_CHUNKS_DELAY);
+  }
+
+  // chunks intersecting the window, undefined until the canvas is loaded
+  getViewChunks(): Array<[number, number]> | undefined {
+    const { width, height, chunk_size } = get(CanvasInfoStore);
+    if (chunk_size === 0) return;
+
+    // bounding rect already has zoom and pan applied
+    const rect = this.canvas.getBoundingClientRect();
+    if (rect.width === 0 || rect.height === 0) return [];
+    const left = Math.max(rect.left, 0);
+    const top = Math.max(rect.top, 0);
+    const right = Math.min(rect.right, window.innerWidth);
+    const bottom = Math.min(rect.bottom, window.innerHeight);
+    if (left >= right || top >= bottom) return [];
+
+    const toChunk = (client: number, start: number, length: number, size: number) =>
+      numberClamp(Math.floor((client - start) / length * size / chunk_size), 0, Math.ceil(size / chunk_size) - 1);
+    const chunk_x_first = toChunk(left, rect.left, rect.width, width);
+    const chunk_x_last = toChunk(right - 1, rect.left, rect.width, width);
+    const chunk_y_first = toChunk(top, rect.top, rect.height, height);
+    const chunk_y_last = toChunk(bottom - 1, rect.top, rect.height, height);
+
+    const chunks: Array<[number, number]> = [];
+    for (let chunk_x = chunk_x_first; chunk_x <= chunk_x_last; chunk_x++) {
+      for (let chunk_y = chunk_y_first; chunk_y <= chunk_y_last; chunk_y++) {
+        chunks.push([chunk_x, chunk_y]);
+      }
+    }
+    return chunks;
+  }
+
   putCanvasPixels(canvasPixels: CanvasPixels) {
     this.pixels = canvasPixels;
     this.updateCanvas();
@@ -212,6 +261,16 @@ export class CanvasElementController {
     if (this.canvas_update_frame_asked) window.requestAnimationFrame(() => this.updateCanvas())
   }
 
+  // put many pixels with a single redraw
+  putPixelsCanvas(pixels: Array<[number, number, Color]>) {
+    if(this.pixels === undefined) return;
+
+    for (const [x, y, color] of pixels) {
+      this.pixels.colors[x + (this.pixels.width * y)] = color;
+    }
+    this.updateCanvas();
+  }
+
   getPixelCanvas(x: number, y: number): Color {
     if(this.pixels === undefined) return [0, 0, 0, 0];
     return this.pixels.colors[x + (this.pixels.width * y)];
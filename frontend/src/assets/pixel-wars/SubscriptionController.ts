@@ -1,31 +1,89 @@
 import type CanvasElementController from './CanvasController';
-import { decodeColor, encodeColor } from './canvas';
-import { ColorPickerStore, OnlineCountStore, TimeoutStore } from './stores';
+import type { Color } from './CanvasController';
+import { chunkLoad, decodeColor, encodeColor, initialLoad } from './canvas';
+import { CanvasInfoStore, CanvasPaletteStore, ColorPickerStore, FeaturesStore, OnlineCountStore, TimeoutStore } from './stores';
 import { get } from 'svelte/store';
 import timeFormat from './utils/timeFormat';
+import {
+  CLOSE_HANDSHAKE_REQUIRED,
+  CLOSE_UNSUPPORTED_VERSION,
+  ERROR_NO_PIXELS_LEFT,
+  decodeServerMessage,
+  encodeHeartbeat,
+  encodeHello,
+  encodePlace,
+  encodeResume,
+  encodeSubscribe,
+} from './protocol';
+
+// chunks per subscribe frame, well below the server frame size limit
+const SUBSCRIBE_BATCH_SIZE = 4096;
+
+function chunkKey(chunkX: number, chunkY: number): string {
+  return `${chunkX},${chunkY}`;
+}
 
 export default class SubscriptionController {
   websocketServer: WebSocket | undefined;
   websocketHeartbeatInterval: number | undefined;
   canvasController: CanvasElementController;
+  // latest placement sequence number received, to resume after reconnecting
+  lastSeq: number | undefined;
+  // placements waiting for an ack, to restore the previous color on error
+  nextRequestId: number;
+  pendingPlacements: Map<number, { x: number, y: number, previousColor: number }>;
+  // chunks in view, the server only sends their updates once subscribed
+  viewChunks: Array<[number, number]> | undefined;
+  subscribedChunks: Set<string>;
+  // once subscribed, chunks out of view miss updates and are loaded again when back in view
+  viewSubscribed: boolean;
+  // updates received while their chunk loads, applied again over the loaded chunk
+  loadingChunks: Map<string, Array<[number, number, Color]>>;
 
   constructor(canvasController: CanvasElementController) {
     this.canvasController = canvasController;
     this.websocketHeartbeatInterval = undefined;
     this.websocketServer = undefined;
+    this.lastSeq = undefined;
+    this.nextRequestId = 1;
+    this.pendingPlacements = new Map();
+    this.viewChunks = undefined;
+    this.subscribedChunks = new Set();
+    this.viewSubscribed = false;
+    this.loadingChunks = new Map();
+  }
+
+  public updateSeq(seq: number | undefined) {
+    if (seq === undefined) return;
+    this.lastSeq = this.lastSeq === undefined ? seq : Math.max(this.lastSeq, seq);
   }
 
   public async createWsConnection() {
     const protocol = window.location.protocol.startsWith("https") ? "wss://" : "ws://";
     this.websocketServer = new WebSocket(protocol + window.location.host + '/websocket');
+    this.websocketServer.binaryType = 'arraybuffer';
     const websocketServerCreated = Date.now();
 
     this.websocketHeartbeatInterval = setInterval(() => {
-      (this.websocketServer as WebSocket).send("h"); // heartbeat
+      (this.websocketServer as WebSocket).send(encodeHeartbeat());
     }, 30*1000); // every 30s
 
     this.websocketServer.addEventListener("message", this.receiveMessageHandler());
 
+    this.websocketServer.addEventListener("open", () => {
+      const websocketServer = this.websocketServer as WebSocket;
+      websocketServer.send(encodeHello());
+
+      // get updates missed while disconnected
+      if (this.lastSeq !== undefined) {
+        websocketServer.send(encodeResume(this.lastSeq));
+      }
+
+      // new session gets every update until it subscribes again
+      this.subscribedChunks = new Set();
+      this.updateSubscriptions();
+    })
+
     this.websocketServer.addEventListener("error", (event) => {
       console.error("WebSocket error: ", event);
     })
@@ -39,12 +97,17 @@ export default class SubscriptionController {
       }
       if(code === 1001) {
         console.info(`WebSocket closed after ${duration} with error code ${code}: Going away`)
+      } else if(code === CLOSE_HANDSHAKE_REQUIRED || code === CLOSE_UNSUPPORTED_VERSION) {
+        // reconnecting would fail the same way, page must be reloaded to get a matching client
+        console.error(`WebSocket closed after ${duration}, protocol not supported by server: `, event.reason);
       } else {
         console.error(`WebSocket closed after ${duration} with error code ${code}`, event);
         console.error(`Websocket closed after ${duration} with following reason: `, event.reason);
 
         console.log("Reopening socket...");
-        this.createWsConnection();
+        // unknown sessions are refused, e.g. after the server store was emptied, get a new one first
+        fetch(window.location.protocol+"//"+window.location.host+'/api/session')
+          .finally(() => this.createWsConnection());
       }
     })
   }
@@ -56,13 +119,18 @@ export default class SubscriptionController {
       return;
     }
     window.dispatchEvent(new CustomEvent<{ done: boolean }>("sessionLoaded", { detail: { done: true } }));
-    TimeoutStore.request();
-
     await this.createWsConnection();
 
+    // @ts-expect-error Custom event viewChunksChanged
+    window.addEventListener("viewChunksChanged", (ev: CustomEvent) => {
+      this.viewChunks = (ev.detail as { chunks: Array<[number, number]> }).chunks;
+      this.updateSubscriptions();
+    })
+
     // @ts-expect-error Custom event pixelClicked
     window.addEventListener("pixelClicked", async (ev: CustomEvent) => {
       const coords = ev.detail as { x: number, y: number };
+      if (!get(FeaturesStore).placement) return;
 
       // do not place if same pixel color
       const color = get(ColorPickerStore);
@@ -74,64 +142,133 @@ export default class SubscriptionController {
       if (timeout.remainingPixels === 0) return;
       timeout.remainingPixels--;
       TimeoutStore.set(timeout);
-      await this.sendUpdate(coords.x, coords.y, color);
+      const previousColor = encodeColor(this.canvasController.getPixelCanvas(x, y));
+      const requestId = await this.sendUpdate(coords.x, coords.y, color);
+      if (requestId !== undefined) {
+        this.pendingPlacements.set(requestId, { x, y, previousColor });
+      }
       this.canvasController.putPixelCanvas(coords.x, coords.y, decodeColor(color));
     })
   }
 
-  public async sendUpdate(x: number, y: number, color: number) {
-    if (color >= 16) throw new Error(`illegal color ${color} must be less than 16...`);
+  public async sendUpdate(x: number, y: number, color: number): Promise<number | undefined> {
+    const colorCount = get(CanvasPaletteStore).length;
+    if (color >= colorCount) throw new Error(`illegal color ${color} must be less than ${colorCount}...`);
     if(this.websocketServer === undefined) return;
-    this.websocketServer.send(this.encodeMessage(x, y, color));
+
+    // request id 0 is used by the server for errors not about a placement
+    const requestId = this.nextRequestId;
+    this.nextRequestId = this.nextRequestId >= 0xFFFFFFFF ? 1 : this.nextRequestId + 1;
+    this.websocketServer.send(encodePlace(requestId, x, y, color));
+    return requestId;
   }
 
-  private encodeMessage(x: number, y: number, color: number) {
-    const buffer = new ArrayBuffer(5);
-    const dataView = new DataView(buffer);
+  // subscribe to chunks entering the view and unsubscribe from the ones leaving it
+  private updateSubscriptions() {
+    const websocketServer = this.websocketServer;
+    if (websocketServer === undefined || websocketServer.readyState !== WebSocket.OPEN) return;
+    if (this.viewChunks === undefined) return;
 
-    dataView.setUint16(0, x, false);
-    dataView.setUint16(2, y, false);
-    dataView.setUint8(4, color);
+    const viewKeys = new Set(this.viewChunks.map(([chunkX, chunkY]) => chunkKey(chunkX, chunkY)));
+    const subscribe = this.viewChunks.filter(([chunkX, chunkY]) => !this.subscribedChunks.has(chunkKey(chunkX, chunkY)));
+    const unsubscribe = [...this.subscribedChunks]
+      .filter(key => !viewKeys.has(key))
+      .map(key => key.split(',').map(s => Number.parseInt(s, 10)) as [number, number]);
 
-    return buffer;
-  }
+    for (let i = 0; i < subscribe.length; i += SUBSCRIBE_BATCH_SIZE) {
+      websocketServer.send(encodeSubscribe(subscribe.slice(i, i + SUBSCRIBE_BATCH_SIZE), true));
+    }
+    for (let i = 0; i < unsubscribe.length; i += SUBSCRIBE_BATCH_SIZE) {
+      websocketServer.send(encodeSubscribe(unsubscribe.slice(i, i + SUBSCRIBE_BATCH_SIZE), false));
+    }
 
-  private decodeMessage(buffer: ArrayBuffer) {
-    const dataView = new DataView(buffer);
+    for (const [chunkX, chunkY] of unsubscribe) {
+      this.subscribedChunks.delete(chunkKey(chunkX, chunkY));
+    }
+    for (const [chunkX, chunkY] of subscribe) {
+      this.subscribedChunks.add(chunkKey(chunkX, chunkY));
+      // unchanged chunks are answered from the browser cache thanks to their ETag
+      if (this.viewSubscribed) this.reloadChunk(chunkX, chunkY);
+    }
+    this.viewSubscribed = true;
+  }
 
-    const x = dataView.getUint16(0, false);
-    const y = dataView.getUint16(2, false);
-    const color = dataView.getUint8(4);
+  private async reloadChunk(chunkX: number, chunkY: number) {
+    const key = chunkKey(chunkX, chunkY);
+    this.loadingChunks.set(key, []);
+    const pixels = await chunkLoad(chunkX, chunkY);
+    const updates = this.loadingChunks.get(key) ?? [];
+    this.loadingChunks.delete(key);
 
-    return { x, y, color };
+    if (pixels === undefined) return;
+    // updates received while loading may be newer than the loaded chunk
+    this.canvasController.putPixelsCanvas([...pixels, ...updates]);
   }
 
   private receiveMessageHandler() {
     // eslint-disable-next-line @typescript-eslint/no-this-alias
     const subscription: SubscriptionController = this;
-    return async (message: MessageEvent<Blob|string>) => {
-      if(typeof(message.data) === 'string')
-      {
-        const [command, ...args] = message.data.split(' ');
-        if(command === '/count')
-        {
-          const count = Number.parseInt(args[0], 10);
-          OnlineCountStore.set(count)
-        } else if(command == 'h')
-        {
-          // heartbeat received, normal
-        }
-        else
-        {
-          // returned unknown content or error
-          console.error(message.data);
-        }
-        return;
-      }
+    return async (message: MessageEvent<ArrayBuffer>) => {
+      const serverMessage = decodeServerMessage(message.data);
+      switch (serverMessage.type) {
+        case 'pixelUpdates':
+          for (const { x, y, color, seq } of serverMessage.updates) {
+            subscription.canvasController.putPixelCanvas(x, y, decodeColor(color));
+            subscription.updateSeq(seq);
 
-      const { x, y, color } = subscription.decodeMessage(await message.data.arrayBuffer());
+            const chunkSize = get(CanvasInfoStore).chunk_size;
+            if (chunkSize !== 0) {
+              subscription.loadingChunks.get(chunkKey(Math.floor(x / chunkSize), Math.floor(y / chunkSize)))?.push([x, y, decodeColor(color)]);
+            }
+          }
+          break;
+        case 'synced':
+          // updates out of view were left out, resume after them
+          subscription.updateSeq(serverMessage.seq);
+          break;
+        case 'onlineCount':
+          OnlineCountStore.set(serverMessage.count);
+          break;
+        case 'cooldown':
+          TimeoutStore.set({
+            lastDate: new Date(serverMessage.lastTimestamp * 1000),
+            nextDate: new Date(serverMessage.nextTimestamp * 1000),
+            remainingPixels: serverMessage.remainingPixels,
+          });
+          break;
+        case 'reload':
+        case 'configChanged':
+          // missed updates are too old, or palette and features changed, load whole canvas again
+          subscription.lastSeq = undefined;
+          subscription.updateSeq(await initialLoad(subscription.canvasController));
+          break;
+        case 'ack':
+          // own placement also comes back as a pixel update, lastSeq only follows those and synced
+          // so updates still waiting for the next tick are resumed after reconnecting
+          subscription.pendingPlacements.delete(serverMessage.requestId);
+          break;
+        case 'error': {
+          console.error(`Server error ${serverMessage.code}: ${serverMessage.message}`);
 
-      subscription.canvasController.putPixelCanvas(x, y, decodeColor(color));
+          const placement = subscription.pendingPlacements.get(serverMessage.requestId);
+          if (placement === undefined) break;
+          subscription.pendingPlacements.delete(serverMessage.requestId);
+
+          // placement refused, undo it locally
+          const { x, y, previousColor } = placement;
+          subscription.canvasController.putPixelCanvas(x, y, decodeColor(previousColor));
+          // pixel was not spent, otherwise the server pushes the cooldown
+          const timeout = get(TimeoutStore);
+          if (timeout.remainingPixels !== null && serverMessage.code !== ERROR_NO_PIXELS_LEFT) {
+            timeout.remainingPixels++;
+            TimeoutStore.set(timeout);
+          }
+          break;
+        }
+        default:
+          // welcome, heartbeat and newer messages
+          break;
+      }
     };
   }
 
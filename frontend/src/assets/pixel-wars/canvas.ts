@@ -1,8 +1,10 @@
 import type CanvasElementController from './CanvasController';
 import type { CanvasPixels, Color } from './CanvasController';
-import { CanvasPaletteStore } from './stores';
+import { CanvasInfoStore, CanvasPaletteStore, FeaturesStore } from './stores';
 
 let ColorPaletteLocal: Color[] = [];
+// canvas size and pixel encoding of the last load, to decode single chunks
+let CanvasLayoutLocal: { size: CanvasInfoSize, bitsPerPixel: number } | undefined = undefined;
 
 export function encodeColor(input: Color): number {
   const input_str = input.toString();
@@ -20,7 +22,8 @@ interface CanvasInfoSize {
   chunkSize: number,
 }
 
-export async function initialLoad(canvasController: CanvasElementController) {
+// load whole canvas, returns the latest placement sequence number included
+export async function initialLoad(canvasController: CanvasElementController): Promise<number | undefined> {
   const canvasJSON = await fetch(window.location.protocol+"//"+window.location.host+'/api/canvas')
       .then(t => t.json())
       .catch(e => {
@@ -30,6 +33,14 @@ export async function initialLoad(canvasController: CanvasElementController) {
 
   //* Size
   const canvasSize = canvasJSON['size'] as CanvasInfoSize;
+  const bitsPerPixel = canvasJSON['bitsPerPixel'] as number;
+  CanvasLayoutLocal = { size: canvasSize, bitsPerPixel };
+  CanvasInfoStore.update(v => ({
+    ...v,
+    width: canvasSize.width,
+    height: canvasSize.height,
+    chunk_size: canvasSize.chunkSize,
+  }));
 
   //* Colors
   ColorPaletteLocal = canvasJSON['colors'].map((e: [number, number, number]) => [...e, 255]);
@@ -38,6 +49,9 @@ export async function initialLoad(canvasController: CanvasElementController) {
     ColorPaletteLocal = palette;
   });
 
+  //* Features
+  FeaturesStore.set(canvasJSON['features']);
+
   //* Pixels
   const canvas_obj_bytes: Record<string, Array<number>> = {};
   for (const chunkRowIndex in canvasJSON['canvas']) {
@@ -54,55 +68,44 @@ export async function initialLoad(canvasController: CanvasElementController) {
   }
 
   canvasController.setSize(canvasSize.width, canvasSize.height);
-  const imageData = canvasStringToColorList(canvas_obj_bytes, canvasSize);
+  const imageData = canvasStringToColorList(canvas_obj_bytes, canvasSize, bitsPerPixel);
   canvasController.putCanvasPixels(imageData);
+
+  return canvasJSON['seq'] as number | undefined;
+}
+
+// load a single chunk, returns its pixels positioned on the canvas
+export async function chunkLoad(chunkX: number, chunkY: number): Promise<Array<[number, number, Color]> | undefined> {
+  if (CanvasLayoutLocal === undefined) return;
+  const { size, bitsPerPixel } = CanvasLayoutLocal;
+
+  const response = await fetch(window.location.protocol+"//"+window.location.host+`/api/canvas/chunk/${chunkX}/${chunkY}`);
+  if (!response.ok) {
+    console.error(`Failed to load chunk ${chunkX},${chunkY}: ${response.status}`);
+    return;
+  }
+  const chunk_bytes = new Uint8Array(await response.arrayBuffer());
+
+  const pixels: Array<[number, number, Color]> = [];
+  chunkColorsForEach(chunkX, chunkY, chunk_bytes, size, bitsPerPixel, (canvas_pos_x, canvas_pos_y, color) => {
+    pixels.push([canvas_pos_x, canvas_pos_y, color]);
+  });
+  return pixels;
 }
 
 function canvasStringToColorList(
   canvasArray: Record<string, Array<number>>,
   canvasSize: CanvasInfoSize,
+  bitsPerPixel: number,
 ): CanvasPixels {
   const canvasColors: Array<Color> = new Array(canvasSize.height * canvasSize.width).fill(null);
   for (const [key, chunk_bytes] of Object.entries(canvasArray))
   {
     // those are reversed somehow
     const [chunk_index_x, chunk_index_y] = key.split(',').map(s => Number.parseInt(s, 10));
-    let chunk_pos_x = 0;
-    let chunk_pos_y = 0;
-    for (let array_i  = 0; array_i < chunk_bytes.length; array_i++)
-    {
-      let canvas_pos_y = chunk_index_y * canvasSize.chunkSize + chunk_pos_y;
-      let canvas_pos_x = chunk_index_x * canvasSize.chunkSize + chunk_pos_x;
-
-      if(canvas_pos_x < canvasSize.width && canvas_pos_y < canvasSize.height)
-      {
-        const color_code = chunk_bytes[array_i] >> 4;
-        const color = decodeColor(color_code);
-        canvasColors[canvas_pos_y * canvasSize.width + canvas_pos_x] = color;
-      }
-
-      chunk_pos_x += 1;
-      if(chunk_pos_x >= canvasSize.chunkSize) {
-        chunk_pos_x = 0;
-        chunk_pos_y += 1;
-      }
-
-      canvas_pos_y = chunk_index_y * canvasSize.chunkSize + chunk_pos_y;
-      canvas_pos_x = chunk_index_x * canvasSize.chunkSize + chunk_pos_x;
-
-      if(canvas_pos_x < canvasSize.width && canvas_pos_y < canvasSize.height)
-      {
-        const color_code = chunk_bytes[array_i] & 15;
-        const color = decodeColor(color_code);
-        canvasColors[canvas_pos_y * canvasSize.width + canvas_pos_x] = color;
-      }
-
-      chunk_pos_x += 1;
-      if(chunk_pos_x >= canvasSize.chunkSize) {
-        chunk_pos_x = 0;
-        chunk_pos_y += 1;
-      }
-    }
+    chunkColorsForEach(chunk_index_x, chunk_index_y, chunk_bytes, canvasSize, bitsPerPixel, (canvas_pos_x, canvas_pos_y, color) => {
+      canvasColors[canvas_pos_y * canvasSize.width + canvas_pos_x] = color;
+    });
   }
 
   return {
@@ -111,3 +114,26 @@ function canvasStringToColorList(
     height:canvasSize.height
   };
 }
+
+// decode the pixels of a chunk, skipping the ones past the canvas edge
+function chunkColorsForEach(
+  chunk_index_x: number,
+  chunk_index_y: number,
+  chunk_bytes: ArrayLike<number>,
+  canvasSize: CanvasInfoSize,
+  bitsPerPixel: number,
+  callback: (canvas_pos_x: number, canvas_pos_y: number, color: Color) => void,
+) {
+  const colorMask = (1 << bitsPerPixel) - 1;
+  for (let pixel_i = 0; pixel_i < canvasSize.chunkSize * canvasSize.chunkSize; pixel_i++)
+  {
+    const canvas_pos_x = chunk_index_x * canvasSize.chunkSize + pixel_i % canvasSize.chunkSize;
+    const canvas_pos_y = chunk_index_y * canvasSize.chunkSize + Math.floor(pixel_i / canvasSize.chunkSize);
+    if(canvas_pos_x >= canvasSize.width || canvas_pos_y >= canvasSize.height) continue;
+
+    // pixels never span two bytes, most significant bits first
+    const bit_offset = pixel_i * bitsPerPixel;
+    const color_code = (chunk_bytes[bit_offset >> 3] >> (8 - bitsPerPixel - (bit_offset & 7))) & colorMask;
+    callback(canvas_pos_x, canvas_pos_y, decodeColor(color_code));
+  }
+}
@@ -0,0 +1,150 @@
+// binary websocket protocol, see backend/src/protocol.rs
+export const PROTOCOL_VERSION = 2;
+
+export const CLOSE_HANDSHAKE_REQUIRED = 4000;
+export const CLOSE_UNSUPPORTED_VERSION = 4001;
+
+const TAG_HELLO = 0x01;
+const TAG_HEARTBEAT = 0x02;
+
+const TAG_PLACE = 0x10;
+const TAG_RESUME = 0x20;
+const TAG_SUBSCRIBE = 0x21;
+const TAG_UNSUBSCRIBE = 0x22;
+
+const TAG_PIXEL_UPDATES = 0x10;
+const TAG_RELOAD = 0x11;
+const TAG_ACK = 0x12;
+const TAG_CONFIG_CHANGED = 0x13;
+const TAG_SYNCED = 0x14;
+const TAG_ONLINE_COUNT = 0x30;
+const TAG_COOLDOWN = 0x31;
+const TAG_ERROR = 0x40;
+
+const PIXEL_UPDATE_SIZE = 13;
+
+export const ERROR_INVALID_MESSAGE = 1;
+export const ERROR_INVALID_POSITION = 2;
+export const ERROR_NO_PIXELS_LEFT = 3;
+export const ERROR_SERVER = 4;
+export const ERROR_INVALID_COLOR = 5;
+export const ERROR_PLACEMENT_DISABLED = 6;
+
+export interface PixelUpdate {
+  x: number,
+  y: number,
+  color: number,
+  seq: number,
+}
+
+export type ServerMessage =
+  | { type: 'welcome', version: number }
+  | { type: 'heartbeat' }
+  | { type: 'pixelUpdates', updates: PixelUpdate[] }
+  | { type: 'reload' }
+  | { type: 'ack', requestId: number, seq: number }
+  | { type: 'configChanged' }
+  | { type: 'synced', seq: number }
+  | { type: 'onlineCount', count: number }
+  | { type: 'cooldown', remainingPixels: number, lastTimestamp: number, nextTimestamp: number }
+  | { type: 'error', code: number, requestId: number, message: string }
+  | { type: 'unknown', tag: number };
+
+export function encodeHello(): ArrayBuffer {
+  return new Uint8Array([TAG_HELLO, PROTOCOL_VERSION]).buffer;
+}
+
+export function encodeHeartbeat(): ArrayBuffer {
+  return new Uint8Array([TAG_HEARTBEAT]).buffer;
+}
+
+export function encodePlace(requestId: number, x: number, y: number, color: number): ArrayBuffer {
+  const buffer = new ArrayBuffer(10);
+  const dataView = new DataView(buffer);
+
+  dataView.setUint8(0, TAG_PLACE);
+  dataView.setUint32(1, requestId, false);
+  dataView.setUint16(5, x, false);
+  dataView.setUint16(7, y, false);
+  dataView.setUint8(9, color);
+
+  return buffer;
+}
+
+export function encodeResume(seq: number): ArrayBuffer {
+  const buffer = new ArrayBuffer(9);
+  const dataView = new DataView(buffer);
+
+  dataView.setUint8(0, TAG_RESUME);
+  dataView.setBigUint64(1, BigInt(seq), false);
+
+  return buffer;
+}
+
+export function encodeSubscribe(chunks: Array<[number, number]>, subscribe = true): ArrayBuffer {
+  const buffer = new ArrayBuffer(1 + chunks.length * 4);
+  const dataView = new DataView(buffer);
+
+  dataView.setUint8(0, subscribe ? TAG_SUBSCRIBE : TAG_UNSUBSCRIBE);
+  chunks.forEach(([chunkX, chunkY], i) => {
+    dataView.setUint16(1 + i * 4, chunkX, false);
+    dataView.setUint16(3 + i * 4, chunkY, false);
+  });
+
+  return buffer;
+}
+
+export function decodeServerMessage(buffer: ArrayBuffer): ServerMessage {
+  const dataView = new DataView(buffer);
+  const tag = dataView.getUint8(0);
+
+  switch (tag) {
+    case TAG_HELLO:
+      return { type: 'welcome', version: dataView.getUint8(1) };
+    case TAG_HEARTBEAT:
+      return { type: 'heartbeat' };
+    case TAG_PIXEL_UPDATES: {
+      const updates: PixelUpdate[] = [];
+      for (let offset = 1; offset + PIXEL_UPDATE_SIZE <= buffer.byteLength; offset += PIXEL_UPDATE_SIZE) {
+        updates.push({
+          x: dataView.getUint16(offset, false),
+          y: dataView.getUint16(offset + 2, false),
+          color: dataView.getUint8(offset + 4),
+          seq: Number(dataView.getBigUint64(offset + 5, false)),
+        });
+      }
+      return { type: 'pixelUpdates', updates };
+    }
+    case TAG_RELOAD:
+      return { type: 'reload' };
+    case TAG_ACK:
+      return {
+        type: 'ack',
+        requestId: dataView.getUint32(1, false),
+        seq: Number(dataView.getBigUint64(5, false)),
+      };
+    case TAG_CONFIG_CHANGED:
+      return { type: 'configChanged' };
+    case TAG_SYNCED:
+      return { type: 'synced', seq: Number(dataView.getBigUint64(1, false)) };
+    case TAG_ONLINE_COUNT:
+      return { type: 'onlineCount', count: dataView.getUint32(1, false) };
+    case TAG_COOLDOWN:
+      return {
+        type: 'cooldown',
+        remainingPixels: dataView.getUint32(1, false),
+        lastTimestamp: Number(dataView.getBigUint64(5, false)),
+        nextTimestamp: Number(dataView.getBigUint64(13, false)),
+      };
+    case TAG_ERROR:
+      return {
+        type: 'error',
+        code: dataView.getUint8(1),
+        requestId: dataView.getUint32(2, false),
+        message: new TextDecoder().decode(buffer.slice(6)),
+      };
+    default:
+      // newer message, ignored
+      return { type: 'unknown', tag };
+  }
+}
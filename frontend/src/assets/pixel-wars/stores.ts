@@ -5,51 +5,12 @@ import type { Color } from "./CanvasController";
 
 export const ColorPickerStore = createStore("COLOR_PICKER", 0, (s) => Number.parseInt(s, 10), v => v);
 
-const rawTimeoutStore = writable({
+// pushed by the server over the websocket, after every placement and refill
+export const TimeoutStore = writable({
     lastDate: new Date(),
     nextDate: new Date(),
-    remainingPixels: null,
-    requestingPixels: false,
+    remainingPixels: null as number | null,
 });
-export const TimeoutStore = {
-    ...rawTimeoutStore,
-    request: async function() {
-        // update request status
-        rawTimeoutStore.update((v) => ({...v, requestingPixels: true }));
-
-        return fetch(window.location.protocol+"//"+window.location.host+'/api/client/timeout')
-            .then(r => r.json())
-            .then(json => {
-                const obj = {
-                    lastDate: new Date(json.lastTimestamp * 1000),
-                    nextDate: new Date(json.nextTimestamp * 1000),
-                    remainingPixels: json.remainingPixels,
-                    requestingPixels: false,
-                };
-                // update data and clear request status
-                rawTimeoutStore.set(obj);
-
-                const restart_duration = obj.nextDate.getTime() - new Date().getTime();
-                if(restart_duration > 0)
-                {
-                    // you will ask for pixels in more than the delay
-                    setTimeout(() => {
-                        this.request();
-                    }, restart_duration + 10);
-                }
-                else {
-                    // you should already ask for them
-                    this.request();
-                }
-
-                return obj;
-            })
-            .finally(() => {
-                // update request status
-                rawTimeoutStore.update((v) => ({...v, requestingPixels: false }));
-            });
-    }
-}
 
 export const CanvasInfoStore = writable({
     canvas_zoom: 5,
@@ -62,8 +23,16 @@ export const CanvasInfoStore = writable({
 
     height: 0,
     width: 0,
+    chunk_size: 0,
 })
 
 export const CanvasPaletteStore = writable(new Array<Color>())
 
+// server features, loaded with the canvas
+export const FeaturesStore = writable({
+    placement: true,
+    history: true,
+    timeTravel: true,
+});
+
 export const OnlineCountStore = writable(0);